@@ -0,0 +1,89 @@
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::time::{SystemTime, UNIX_EPOCH};
+use hyper::Client;
+use openssl::crypto::hash::Type;
+use openssl::crypto::hmac::hmac;
+use openssl::crypto::rand::rand_bytes;
+use rustc_serialize::hex::ToHex;
+use serde_json;
+use serde_json::Value as JsonValue;
+
+use conf::Conf;
+use error::{Reason, Error, Result};
+use header::HubSignature;
+
+/// Asks a running koukku server to update a project.
+/// The request is signed using the project key, and can only be used once.
+pub fn trigger(conf: &Conf,
+               url: Option<&str>,
+               id: &str,
+               branch: Option<&str>,
+               commit: Option<&str>)
+               -> Result<String> {
+    let body = json_body(vec![("branch", branch), ("commit", commit)]);
+    post_action(conf, url, id, "trigger", body)
+}
+
+/// Asks a running koukku server to roll a project back to a previously
+/// deployed commit, or to the deploy before the current one.
+pub fn rollback(conf: &Conf, url: Option<&str>, id: &str, to: Option<&str>) -> Result<String> {
+    let body = json_body(vec![("to", to)]);
+    post_action(conf, url, id, "rollback", body)
+}
+
+fn post_action(conf: &Conf,
+               url: Option<&str>,
+               id: &str,
+               action: &str,
+               mut body: BTreeMap<String, JsonValue>)
+               -> Result<String> {
+    let project = try!(conf.get_project(id)
+                           .ok_or(Error::app(Reason::MissingProject, "No project found")));
+    // The server accepts each nonce once, and only with a recent timestamp
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    body.insert("timestamp".to_owned(), JsonValue::U64(now));
+    body.insert("nonce".to_owned(), JsonValue::String(rand_bytes(16).to_hex()));
+    let body = try!(serde_json::to_string(&JsonValue::Object(body)));
+    let signature = HubSignature {
+        digest: Type::SHA1,
+        hash: hmac(Type::SHA1, project.key.as_bytes(), body.as_bytes()),
+    };
+    let base_url = url.map(|u| u.to_owned()).unwrap_or(server_url(&conf.server));
+    let target = format!("{}/projects/{}/{}",
+                         base_url.trim_right_matches('/'),
+                         id,
+                         action);
+
+    let client = Client::new();
+    let mut response = try!(client.post(&target[..])
+                                  .header(signature)
+                                  .body(&body[..])
+                                  .send());
+    let mut text = String::new();
+    let _ = try!(response.read_to_string(&mut text));
+    if response.status.is_success() {
+        Ok(text)
+    } else {
+        let msg = format!("Request to {} failed with status {}: {}",
+                          action,
+                          response.status,
+                          text);
+        Err(Error::app(Reason::CommandFailed, msg))
+    }
+}
+
+fn json_body(fields: Vec<(&str, Option<&str>)>) -> BTreeMap<String, JsonValue> {
+    let mut body = BTreeMap::new();
+    for (key, value) in fields {
+        if let Some(v) = value {
+            body.insert(key.to_owned(), JsonValue::String(v.to_owned()));
+        }
+    }
+    body
+}
+
+// The server may listen on all interfaces, which can't be connected to directly
+fn server_url(server: &str) -> String {
+    format!("http://{}", server.replace("0.0.0.0", "127.0.0.1"))
+}
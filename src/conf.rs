@@ -1,14 +1,33 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::env;
 use std::fmt;
+use std::fs::File;
+use std::io::Read;
 use std::str::FromStr;
+use std::time::Duration;
 use ini::Ini;
 use std::error::Error as StdError;
 
 use error::{Reason, Error};
+use git::BackendKind;
+use github::{self, ReportMode};
+use lock::LockPolicy;
+use logging::LogFormat;
+use ratelimit::Limit;
 
 const DEFAULT_BRANCH: &'static str = "master";
 const DEFAULT_GIT_PATH: &'static str = "/usr/bin/git";
 const DEFAULT_SERVER: &'static str = "localhost:8888";
+const DEFAULT_MAX_BODY_SIZE: u64 = 5 * 1024 * 1024;
+const DEFAULT_TIMEOUT: u64 = 30;
+const DEFAULT_LOCK_TIMEOUT: u64 = 600;
+const DEFAULT_READY_QUEUE_SIZE: usize = 10;
+const DEFAULT_SENDMAIL_PATH: &'static str = "/usr/sbin/sendmail";
+const NOTIFY_SUFFIX: &'static str = ".notify";
+const DEFAULT_GITHUB_ENVIRONMENT: &'static str = "production";
+const DEFAULT_CONTAINER_RUNTIME: &'static str = "podman";
+// Passed to commands run as another user when env_allowlist isn't set
+const DEFAULT_ENV_ALLOWLIST: &'static [&'static str] = &["PATH", "LANG", "LC_ALL", "TZ"];
 
 pub type Projects = HashMap<String, Project>;
 
@@ -16,8 +35,17 @@ pub type Projects = HashMap<String, Project>;
 pub struct Conf {
     pub server: String,
     pub threads: Option<usize>,
+    pub max_body_size: u64,
+    pub read_timeout: Duration,
+    pub write_timeout: Duration,
+    pub ip_limit: Option<Limit>,
+    pub ready_queue_size: usize,
+    pub log_format: LogFormat,
+    pub dry_run: bool,
     pub location: String,
     pub gitpath: String,
+    pub git_backend: BackendKind,
+    pub github_api: String,
     pub projects: Projects,
 }
 
@@ -31,15 +59,50 @@ impl Conf {
         let server = s.get("server").unwrap_or(&default_server);
         let threads = try!(optional_from_str::<usize>(s.get("threads"))
                                .map_err(|err| err.description().to_owned()));
+        let max_body_size = try!(from_str_or(s.get("max_body_size"), DEFAULT_MAX_BODY_SIZE)
+                                     .map_err(|err| err.description().to_owned()));
+        // A zero timeout would disable the timeout instead
+        let read_timeout = match from_str_or(s.get("read_timeout"), DEFAULT_TIMEOUT) {
+            Ok(0) | Err(_) => return Err("Invalid read_timeout value".to_owned()),
+            Ok(timeout) => timeout,
+        };
+        let write_timeout = match from_str_or(s.get("write_timeout"), DEFAULT_TIMEOUT) {
+            Ok(0) | Err(_) => return Err("Invalid write_timeout value".to_owned()),
+            Ok(timeout) => timeout,
+        };
+        let ip_limit = try!(limit_from_map(s, "ip_rate_limit", "ip_rate_burst"));
+        let ready_queue_size = try!(from_str_or(s.get("ready_queue_size"),
+                                                DEFAULT_READY_QUEUE_SIZE)
+                                        .map_err(|err| err.description().to_owned()));
+        let log_format = try!(from_str_or(s.get("log_format"), LogFormat::Text));
+        let dry_run = try!(from_str_or(s.get("dry_run"), false)
+                               .map_err(|err| err.description().to_owned()));
         let location = try!(s.get("location").ok_or("No location found".to_owned()));
         let gitpath = s.get("gitpath").unwrap_or(&default_gitpath);
+        let git_backend = try!(from_str_or(s.get("git_backend"), BackendKind::Cli));
+        let github_api = s.get("github_api")
+                          .cloned()
+                          .unwrap_or(github::DEFAULT_API_URL.to_owned());
         let projects = try!(ini_to_projects(ini).map_err(|err| err.to_owned()));
+        for project in projects.values() {
+            try!(git_backend.check_options(&project.git, &project.exec)
+                            .map_err(|err| format!("Project {}: {}", project.id, err)));
+        }
 
         Ok(Conf {
             server: server.to_owned(),
             threads: threads,
+            max_body_size: max_body_size,
+            read_timeout: Duration::from_secs(read_timeout),
+            write_timeout: Duration::from_secs(write_timeout),
+            ip_limit: ip_limit,
+            ready_queue_size: ready_queue_size,
+            log_format: log_format,
+            dry_run: dry_run,
             location: location.to_owned(),
             gitpath: gitpath.to_owned(),
+            git_backend: git_backend,
+            github_api: github_api,
             projects: projects,
         })
     }
@@ -49,8 +112,8 @@ impl Conf {
         Conf::from_ini(&ini).map_err(|err| Error::app(Reason::InvalidConf, err))
     }
 
-    pub fn get_project(&self, repo: &str) -> Option<&Project> {
-        self.projects.get(repo)
+    pub fn get_project(&self, id: &str) -> Option<&Project> {
+        self.projects.get(id)
     }
 }
 
@@ -61,22 +124,69 @@ fn optional_from_str<F: FromStr>(opt_s: Option<&String>) -> Result<Option<F>, F:
     }
 }
 
-fn ini_to_projects(ini: &Ini) -> Result<Projects, &str> {
-    ini.iter()
-       .filter_map(|pair| {
-           let (key, vs) = pair;
-           key.as_ref().map(|k| (k, vs))
-       })
-       .map(|pair| {
-           let (key, vs) = pair;
-           Project::from_map(key, vs)
-       })
-       .collect::<Result<Vec<_>, &str>>()
-       .map(|projects| {
-           projects.into_iter()
-                   .map(|p| (p.repo.to_owned(), p))
-                   .collect::<HashMap<_, _>>()
-       })
+fn from_str_or<F: FromStr>(opt_s: Option<&String>, default: F) -> Result<F, F::Err> {
+    optional_from_str(opt_s).map(|opt| opt.unwrap_or(default))
+}
+
+fn limit_from_map(props: &HashMap<String, String>,
+                  rate_key: &str,
+                  burst_key: &str)
+                  -> Result<Option<Limit>, &'static str> {
+    let per_minute = match optional_from_str::<u32>(props.get(rate_key)) {
+        Ok(Some(0)) | Err(_) => return Err("Invalid rate limit"),
+        Ok(rate) => rate,
+    };
+    let burst = try!(optional_from_str::<u32>(props.get(burst_key))
+                         .map_err(|_| "Invalid rate limit burst"));
+    Ok(per_minute.map(|rate| {
+        Limit {
+            per_minute: rate,
+            burst: burst.unwrap_or(rate).max(1),
+        }
+    }))
+}
+
+// Sections named `<id>.notify` configure the notifications of the project `<id>`
+fn ini_to_projects(ini: &Ini) -> Result<Projects, &'static str> {
+    let mut projects = HashMap::new();
+    for (key, props) in ini.iter() {
+        let id = match *key {
+            Some(ref id) => id,
+            None => continue,
+        };
+        if id.ends_with(NOTIFY_SUFFIX) {
+            let project_id = id.trim_right_matches(NOTIFY_SUFFIX);
+            if ini.section(Some(project_id)).is_none() {
+                return Err("Notify section found without a project");
+            }
+            continue;
+        }
+        let notify = match ini.section(Some(format!("{}{}", id, NOTIFY_SUFFIX))) {
+            Some(n) => Some(try!(Notify::from_map(n))),
+            None => None,
+        };
+        let project = try!(Project::from_map(id, props, notify));
+        projects.insert(project.id.to_owned(), project);
+    }
+    try!(check_routes(&projects));
+    Ok(projects)
+}
+
+// Every webhook must lead to exactly one project: routed projects by their
+// path, and the rest by their repository
+fn check_routes(projects: &Projects) -> Result<(), &'static str> {
+    let mut paths = HashSet::new();
+    let mut repos = HashSet::new();
+    for project in projects.values() {
+        match project.path {
+            Some(ref path) if !paths.insert(path) => return Err("Duplicate project path"),
+            None if !repos.insert(&project.repo) => {
+                return Err("Projects without a path must have different repos")
+            }
+            _ => (),
+        }
+    }
+    Ok(())
 }
 
 impl fmt::Display for Conf {
@@ -102,26 +212,360 @@ pub struct Project {
     pub repo: String,
     pub branch: String,
     pub command: String,
+    pub pre_command: Option<String>,
+    pub post_command: Option<String>,
+    /// Run when any step of a deploy fails
+    pub on_failure: Option<String>,
     pub key: String,
+    pub limit: Option<Limit>,
+    pub path: Option<String>,
+    pub dry_run: bool,
+    /// Number of releases to keep when deploying into release directories
+    pub releases: Option<usize>,
+    pub notify: Option<Notify>,
+    /// API token for reporting deploys to GitHub
+    pub github_token: Option<String>,
+    pub github_report: ReportMode,
+    pub github_environment: String,
+    pub git: GitOptions,
+    pub exec: ExecOptions,
+    pub on_locked: LockPolicy,
+    /// How long to wait for another update of the project to finish
+    pub lock_timeout: Duration,
 }
 
 impl Project {
-    fn from_map(id: &str, props: &HashMap<String, String>) -> Result<Project, &'static str> {
+    fn from_map(id: &str,
+                props: &HashMap<String, String>,
+                notify: Option<Notify>)
+                -> Result<Project, &'static str> {
         let default_branch = DEFAULT_BRANCH.to_owned();
         let repo = try!(props.get("repo").ok_or("No repo found"));
         let branch = props.get("branch").unwrap_or(&default_branch);
         let command = try!(props.get("command").ok_or("No command found"));
         let key = try!(props.get("key").ok_or("No key found"));
+        let limit = try!(limit_from_map(props, "rate_limit", "rate_burst"));
+        let dry_run = try!(from_str_or(props.get("dry_run"), false)
+                               .map_err(|_| "Invalid dry_run value"));
+        let path = match props.get("path") {
+            Some(p) => Some(try!(normalize_path(p))),
+            None => None,
+        };
+        let releases = match optional_from_str::<usize>(props.get("releases")) {
+            Ok(Some(0)) | Err(_) => return Err("Invalid releases value"),
+            Ok(r) => r,
+        };
+        let github_report = try!(from_str_or(props.get("github_report"), ReportMode::Status));
+        let on_locked = try!(from_str_or(props.get("on_locked"), LockPolicy::Wait));
+        let lock_timeout = try!(from_str_or(props.get("lock_timeout"), DEFAULT_LOCK_TIMEOUT)
+                                    .map_err(|_| "Invalid lock_timeout value"));
+        let github_environment = props.get("github_environment")
+                                      .cloned()
+                                      .unwrap_or(DEFAULT_GITHUB_ENVIRONMENT.to_owned());
         Ok(Project {
             id: id.to_owned(),
             repo: repo.to_owned(),
             branch: branch.to_owned(),
             command: command.to_owned(),
+            pre_command: props.get("pre_command").cloned(),
+            post_command: props.get("post_command").cloned(),
+            on_failure: props.get("on_failure").cloned(),
             key: key.to_owned(),
+            limit: limit,
+            path: path,
+            dry_run: dry_run,
+            releases: releases,
+            notify: notify,
+            github_token: props.get("github_token").cloned(),
+            github_report: github_report,
+            github_environment: github_environment,
+            git: try!(GitOptions::from_map(props)),
+            exec: try!(ExecOptions::from_map(props)),
+            on_locked: on_locked,
+            lock_timeout: Duration::from_secs(lock_timeout),
+        })
+    }
+}
+
+/// Options applied when cloning and updating a project repository
+#[derive(Clone)]
+pub struct GitOptions {
+    pub depth: Option<u32>,
+    pub filter: Option<String>,
+    pub sparse_paths: Vec<String>,
+    pub submodules: bool,
+    pub lfs: bool,
+    pub on_dirty: DirtyPolicy,
+    /// Private key used instead of the global SSH configuration
+    pub ssh_key: Option<String>,
+    pub known_hosts: Option<String>,
+    /// HTTPS token used instead of the global git credentials
+    pub token: Option<TokenSource>,
+}
+
+impl GitOptions {
+    fn from_map(props: &HashMap<String, String>) -> Result<GitOptions, &'static str> {
+        let depth = match optional_from_str::<u32>(props.get("depth")) {
+            Ok(Some(0)) | Err(_) => return Err("Invalid depth value"),
+            Ok(d) => d,
+        };
+        let submodules = match props.get("submodules").map(|s| &s[..]) {
+            None | Some("none") => false,
+            Some("recursive") => true,
+            Some(_) => return Err("Submodules must be either none or recursive"),
+        };
+        let lfs = try!(from_str_or(props.get("lfs"), false).map_err(|_| "Invalid lfs value"));
+        let on_dirty = try!(from_str_or(props.get("on_dirty"), DirtyPolicy::Fail));
+        let ssh_key = props.get("ssh_key").cloned();
+        let known_hosts = props.get("known_hosts").cloned();
+        if known_hosts.is_some() && ssh_key.is_none() {
+            return Err("known_hosts requires ssh_key");
+        }
+        let token = match (props.get("token_file"), props.get("token_env")) {
+            (Some(_), Some(_)) => return Err("Only one of token_file and token_env can be set"),
+            (Some(path), None) => Some(TokenSource::File(path.to_owned())),
+            (None, Some(var)) => Some(TokenSource::Env(var.to_owned())),
+            (None, None) => None,
+        };
+        if token.is_some() && ssh_key.is_some() {
+            return Err("ssh_key can't be used with an HTTPS token");
+        }
+        Ok(GitOptions {
+            depth: depth,
+            filter: props.get("filter").cloned(),
+            sparse_paths: props.get("sparse_paths").map(|p| split_list(p)).unwrap_or(Vec::new()),
+            submodules: submodules,
+            lfs: lfs,
+            on_dirty: on_dirty,
+            ssh_key: ssh_key,
+            known_hosts: known_hosts,
+            token: token,
+        })
+    }
+}
+
+/// The options of a project that doesn't set any of them
+impl Default for GitOptions {
+    fn default() -> GitOptions {
+        GitOptions::from_map(&HashMap::new()).unwrap()
+    }
+}
+
+/// How the update command and the hooks of a project are run
+#[derive(Clone)]
+pub struct ExecOptions {
+    /// User and group to switch to. Requires running koukku as root.
+    pub user: Option<String>,
+    pub group: Option<String>,
+    pub umask: Option<u32>,
+    /// CPU time in seconds
+    pub cpu_limit: Option<u64>,
+    /// Address space in bytes
+    pub memory_limit: Option<u64>,
+    pub open_files_limit: Option<u64>,
+    /// When set, only these variables are passed from the environment of koukku
+    pub env_allowlist: Option<Vec<String>>,
+    /// Run in new namespaces with a read-only filesystem. Linux only.
+    pub sandbox: bool,
+    /// Paths writable in the sandbox, in addition to the working directory
+    pub writable_paths: Vec<String>,
+    /// Container image to run the update command in
+    pub image: Option<String>,
+    pub container_runtime: String,
+}
+
+impl ExecOptions {
+    fn from_map(props: &HashMap<String, String>) -> Result<ExecOptions, &'static str> {
+        let umask = match props.get("umask") {
+            Some(m) => {
+                match u32::from_str_radix(m, 8) {
+                    Ok(mask) if mask <= 0o777 => Some(mask),
+                    _ => return Err("Invalid umask value"),
+                }
+            }
+            None => None,
+        };
+        let cpu_limit = try!(optional_from_str::<u64>(props.get("cpu_limit"))
+                                 .map_err(|_| "Invalid cpu_limit value"));
+        let memory_limit = match try!(optional_from_str::<u64>(props.get("memory_limit"))
+                                          .map_err(|_| "Invalid memory_limit value")) {
+            Some(mib) => {
+                Some(try!(mib.checked_mul(1024 * 1024).ok_or("memory_limit is too large")))
+            }
+            None => None,
+        };
+        let open_files_limit = try!(optional_from_str::<u64>(props.get("open_files_limit"))
+                                        .map_err(|_| "Invalid open_files_limit value"));
+        let sandbox = try!(from_str_or(props.get("sandbox"), false)
+                               .map_err(|_| "Invalid sandbox value"));
+        let writable_paths = props.get("writable_paths")
+                                  .map(|p| split_list(p))
+                                  .unwrap_or(Vec::new());
+        if sandbox && !cfg!(target_os = "linux") {
+            return Err("sandbox is only supported on Linux");
+        }
+        let image = props.get("image").cloned();
+        if sandbox && image.is_some() {
+            return Err("image can't be used with sandbox");
+        }
+        let user = props.get("user").cloned();
+        // Root keeps the capabilities to undo the read-only mounts of the sandbox
+        if sandbox && user.is_none() {
+            return Err("sandbox requires user");
+        }
+        // The environment of koukku isn't leaked to other users by default
+        let env_allowlist = match props.get("env_allowlist") {
+            Some(list) => Some(split_list(list)),
+            None if user.is_some() => {
+                Some(DEFAULT_ENV_ALLOWLIST.iter().map(|name| name.to_string()).collect())
+            }
+            None => None,
+        };
+        Ok(ExecOptions {
+            user: user,
+            group: props.get("group").cloned(),
+            umask: umask,
+            cpu_limit: cpu_limit,
+            memory_limit: memory_limit,
+            open_files_limit: open_files_limit,
+            env_allowlist: env_allowlist,
+            sandbox: sandbox,
+            writable_paths: writable_paths,
+            image: image,
+            container_runtime: props.get("container_runtime")
+                                    .cloned()
+                                    .unwrap_or(DEFAULT_CONTAINER_RUNTIME.to_owned()),
+        })
+    }
+}
+
+/// The options of a project that doesn't set any of them
+impl Default for ExecOptions {
+    fn default() -> ExecOptions {
+        ExecOptions::from_map(&HashMap::new()).unwrap()
+    }
+}
+
+/// Where the HTTPS token of a project is read from
+#[derive(Clone)]
+pub enum TokenSource {
+    File(String),
+    Env(String),
+}
+
+impl TokenSource {
+    /// The token is read on every use, so that rotated tokens are picked up
+    /// without a restart. Errors never include the token itself.
+    pub fn read(&self) -> Result<String, Error> {
+        let token = match *self {
+            TokenSource::File(ref path) => {
+                let mut token = String::new();
+                let _ = try!(File::open(path).and_then(|mut f| f.read_to_string(&mut token)));
+                token
+            }
+            TokenSource::Env(ref var) => {
+                try!(env::var(var).map_err(|_| {
+                    Error::app(Reason::InvalidConf,
+                               format!("Token variable {} is not set", var))
+                }))
+            }
+        };
+        let token = token.trim();
+        if token.is_empty() {
+            Err(Error::app(Reason::InvalidConf, "HTTPS token is empty"))
+        } else {
+            Ok(token.to_owned())
+        }
+    }
+}
+
+/// What to do when the local repository can't be updated cleanly
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DirtyPolicy {
+    Fail,
+    Reset,
+    Reclone,
+}
+
+impl DirtyPolicy {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            DirtyPolicy::Fail => "fail",
+            DirtyPolicy::Reset => "reset",
+            DirtyPolicy::Reclone => "reclone",
+        }
+    }
+}
+
+impl FromStr for DirtyPolicy {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<DirtyPolicy, &'static str> {
+        match s {
+            "fail" => Ok(DirtyPolicy::Fail),
+            "reset" => Ok(DirtyPolicy::Reset),
+            "reclone" => Ok(DirtyPolicy::Reclone),
+            _ => Err("on_dirty must be one of fail, reset or reclone"),
+        }
+    }
+}
+
+/// Where to send notifications about finished deploys
+#[derive(Clone)]
+pub struct Notify {
+    pub webhook: Option<String>,
+    pub email: Vec<String>,
+    pub sendmail: String,
+    pub command: Option<String>,
+    /// Failures are always notified, successes only when this is set
+    pub on_success: bool,
+    /// Time limit for each notification target
+    pub timeout: Duration,
+}
+
+impl Notify {
+    fn from_map(props: &HashMap<String, String>) -> Result<Notify, &'static str> {
+        let email = props.get("email").map(|e| split_list(e)).unwrap_or(Vec::new());
+        let on_success = try!(from_str_or(props.get("on_success"), true)
+                                  .map_err(|_| "Invalid on_success value"));
+        let timeout = match from_str_or(props.get("timeout"), DEFAULT_TIMEOUT) {
+            Ok(0) | Err(_) => return Err("Invalid notification timeout"),
+            Ok(secs) => Duration::from_secs(secs),
+        };
+        Ok(Notify {
+            webhook: props.get("webhook").cloned(),
+            email: email,
+            sendmail: props.get("sendmail")
+                           .cloned()
+                           .unwrap_or(DEFAULT_SENDMAIL_PATH.to_owned()),
+            command: props.get("command").cloned(),
+            on_success: on_success,
+            timeout: timeout,
         })
     }
 }
 
+// Splits a comma-separated list of values
+fn split_list(list: &str) -> Vec<String> {
+    list.split(',')
+        .map(|v| v.trim().to_owned())
+        .filter(|v| !v.is_empty())
+        .collect()
+}
+
+/// Normalizes a route path so that it can be compared against request paths
+pub fn normalize_path(path: &str) -> Result<String, &'static str> {
+    if !path.starts_with('/') {
+        return Err("Path must start with /");
+    }
+    let trimmed = path.trim_right_matches('/');
+    if trimmed.is_empty() {
+        Ok("/".to_owned())
+    } else {
+        Ok(trimmed.to_owned())
+    }
+}
+
 impl fmt::Display for Project {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f,
@@ -132,3 +576,47 @@ impl fmt::Display for Project {
                self.command)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use ini::Ini;
+    use super::Conf;
+
+    fn parse(project: &str) -> Result<Conf, String> {
+        let text = format!("location = /tmp/koukku\n[site]\nrepo = Lepovirta/site\nkey = foobar\n\
+                            command = deploy.sh\n{}",
+                           project);
+        Conf::from_ini(&Ini::load_from_str(&text).unwrap())
+    }
+
+    #[test]
+    fn sandbox_requires_user() {
+        assert!(parse("sandbox = true\n").is_err());
+        assert!(parse("sandbox = true\nuser = nobody\n").is_ok());
+    }
+
+    #[test]
+    fn zero_timeouts_are_rejected() {
+        for option in &["read_timeout", "write_timeout"] {
+            let conf = |value| {
+                let text = format!("location = /tmp/koukku\n{} = {}\n", option, value);
+                Conf::from_ini(&Ini::load_from_str(&text).unwrap())
+            };
+            assert!(conf(0).is_err());
+            assert!(conf(1).is_ok());
+        }
+    }
+
+    #[test]
+    fn ambiguous_routes_are_rejected() {
+        let with_other = |other: &str| {
+            parse(&format!("path = /hooks/site\n[other]\nkey = foobar\ncommand = deploy.sh\n{}",
+                           other))
+        };
+
+        assert!(with_other("repo = Lepovirta/site\npath = /hooks/other\n").is_ok());
+        assert!(with_other("repo = Lepovirta/other\npath = /hooks/site/\n").is_err());
+        let unrouted = "[third]\nrepo = Lepovirta/site\nkey = foobar\ncommand = deploy.sh\n";
+        assert!(parse(unrouted).is_err());
+    }
+}
@@ -3,12 +3,16 @@ use std::error::Error as StdError;
 use std::str::Utf8Error;
 use std::sync::mpsc::{SendError, RecvError};
 use std::sync::PoisonError;
+use std::time::Duration;
 use hyper::error::Error as HyperError;
 use ini::ini::Error as IniError;
 use rustc_serialize::hex::FromHexError;
 use serde_json::error::Error as JsonError;
+#[cfg(feature = "libgit2")]
+use git2::Error as GitError;
 
-use self::Error::{Hyper, App, Utf8, Io, Ini, Hex, Json, Mutex, Channel};
+use self::Error::{Hyper, App, RateLimited, CommandExit, Utf8, Io, Ini, Hex, Json, Mutex,
+                  Channel};
 
 pub type Result<T> = result::Result<T, Error>;
 
@@ -17,17 +21,23 @@ pub enum Reason {
     InvalidConf,
     InvalidSignature,
     InvalidRepository,
-    InvalidBranch,
     InvalidPath,
     MissingHeader,
     MissingFields,
     MissingProject,
+    MissingRevision,
+    PayloadTooLarge,
+    InvalidRevision,
+    ReplayedRequest,
     CommandFailed,
 }
 
 #[derive(Debug)]
 pub enum Error {
     App(Reason, String),
+    RateLimited(String, u64),
+    /// Description, exit code and captured output of a failed command
+    CommandExit(String, i32, String),
     Ini(String),
     Mutex(String),
     Channel(String),
@@ -36,18 +46,76 @@ pub enum Error {
     Io(io::Error),
     Hex(FromHexError),
     Json(JsonError),
+    #[cfg(feature = "libgit2")]
+    Git(GitError),
+}
+
+impl Reason {
+    /// Stable identifier for the reason, suitable for API responses
+    pub fn code(&self) -> &'static str {
+        match *self {
+            Reason::InvalidConf => "invalid_conf",
+            Reason::InvalidSignature => "invalid_signature",
+            Reason::InvalidRepository => "invalid_repository",
+            Reason::InvalidPath => "invalid_path",
+            Reason::MissingHeader => "missing_header",
+            Reason::MissingFields => "missing_fields",
+            Reason::MissingProject => "missing_project",
+            Reason::MissingRevision => "missing_revision",
+            Reason::PayloadTooLarge => "payload_too_large",
+            Reason::InvalidRevision => "invalid_revision",
+            Reason::ReplayedRequest => "replayed_request",
+            Reason::CommandFailed => "command_failed",
+        }
+    }
 }
 
 impl Error {
     pub fn app<S: Into<String>>(reason: Reason, desc: S) -> Error {
         App(reason, desc.into())
     }
+
+    pub fn rate_limited(retry_after: Duration) -> Error {
+        let secs = retry_after.as_secs();
+        RateLimited(format!("Rate limit exceeded. Retry after {} seconds", secs), secs)
+    }
+
+    /// The output of a failed command, or the description of other errors
+    pub fn output(&self) -> String {
+        match *self {
+            CommandExit(_, code, ref output) if !output.is_empty() => {
+                format!("{}\nExited with status {}", output.trim_right(), code)
+            }
+            _ => self.to_string(),
+        }
+    }
+
+    /// Stable identifier for the error, suitable for API responses
+    pub fn code(&self) -> &'static str {
+        match *self {
+            App(ref reason, _) => reason.code(),
+            RateLimited(..) => "rate_limited",
+            CommandExit(..) => "command_failed",
+            Ini(_) => "invalid_conf",
+            Mutex(_) => "internal_error",
+            Channel(_) => "internal_error",
+            Hyper(_) => "invalid_request",
+            Utf8(_) => "invalid_encoding",
+            Io(_) => "io_error",
+            Hex(_) => "invalid_encoding",
+            Json(_) => "invalid_json",
+            #[cfg(feature = "libgit2")]
+            Error::Git(_) => "git_error",
+        }
+    }
 }
 
 impl StdError for Error {
     fn description(&self) -> &str {
         match *self {
             App(_, ref s) => &s,
+            RateLimited(ref s, _) => &s,
+            CommandExit(ref s, _, _) => &s,
             Ini(ref s) => &s,
             Mutex(ref s) => &s,
             Channel(ref s) => &s,
@@ -56,6 +124,8 @@ impl StdError for Error {
             Io(ref err) => err.description(),
             Hex(ref err) => err.description(),
             Json(ref err) => err.description(),
+            #[cfg(feature = "libgit2")]
+            Error::Git(ref err) => err.message(),
         }
     }
 
@@ -65,6 +135,8 @@ impl StdError for Error {
             Utf8(ref err) => Some(err),
             Io(ref err) => Some(err),
             Json(ref err) => Some(err),
+            #[cfg(feature = "libgit2")]
+            Error::Git(ref err) => Some(err),
             _ => None,
         }
     }
@@ -112,6 +184,13 @@ impl From<JsonError> for Error {
     }
 }
 
+#[cfg(feature = "libgit2")]
+impl From<GitError> for Error {
+    fn from(err: GitError) -> Error {
+        Error::Git(err)
+    }
+}
+
 impl<T> From<PoisonError<T>> for Error {
     fn from(err: PoisonError<T>) -> Error {
         Mutex(format!("{}", err))
@@ -1,166 +1,1207 @@
-use std::str;
-use std::path::Path;
+use std::fs;
+use std::os::unix;
+use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio, Output};
+use std::sync::Arc;
 use std::sync::mpsc::Receiver;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use conf::{Conf, Project};
+use conf::{Conf, Project, ExecOptions, GitOptions, DirtyPolicy};
 use error::{Reason, Result, Error};
+use git::{self, GitBackend, failure_message};
+use github::Reporter;
+use history::{self, History, Entry};
+use job::Job;
+use lock;
+use logging::{self, Context};
+use metrics::Metrics;
+use notify::{self, Deploy};
+use libc;
+use privileges::{self, RunAs};
+#[cfg(target_os = "linux")]
+use sandbox;
+use status::Status;
 
-type BytesResult = Result<Vec<u8>>;
+// Layout of projects deployed into release directories:
+// the repository is kept up to date in `repo`, each deployed commit is
+// checked out to `releases/<sha>`, and `current` links to the live release.
+const REPO_DIR: &'static str = "repo";
+const RELEASES_DIR: &'static str = "releases";
+const CURRENT_LINK: &'static str = "current";
+
+/// Where the output of the update command goes
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputMode {
+    Capture,
+    Inherit,
+}
 
 pub struct Executor {
     conf: Conf,
-    rx: Receiver<String>,
+    status: Arc<Status>,
+    history: History,
+    output: OutputMode,
 }
 
 impl Executor {
-    pub fn new(conf: Conf, rx: Receiver<String>) -> Executor {
+    pub fn new(conf: Conf, status: Arc<Status>) -> Executor {
+        let history = History::new(&conf.location);
         Executor {
             conf: conf,
-            rx: rx,
+            status: status,
+            history: history,
+            output: OutputMode::Capture,
         }
     }
 
-    pub fn start(&self) {
+    pub fn with_output(self, output: OutputMode) -> Executor {
+        Executor { output: output, ..self }
+    }
+
+    pub fn start(&self, rx: Receiver<Job>) {
+        let _running = self.status.executor_running();
         loop {
-            match self.rx.recv() {
-                Ok(repo) => self.run(&repo),
+            match rx.recv() {
+                Ok(job) => {
+                    self.status.job_dequeued();
+                    self.run(&job)
+                }
                 Err(err) => error!("Error occurred while reading updates: {}", err),
             }
         }
     }
 
-    pub fn run(&self, repo: &str) {
-        match self.update_repo(repo) {
+    pub fn run(&self, job: &Job) {
+        let repo = self.conf.get_project(&job.project).map(|p| &p.repo[..]);
+        logging::set_context(Context::for_job(job, repo));
+        info!("Starting job {}", job.id);
+        match self.execute(job) {
             Ok(_) => (),
-            Err(err) => error!("Failed to update repository {}: {}", repo, err),
+            Err(err) => error!("Failed to update project {}: {}", job.project, err),
+        }
+        logging::clear_context();
+    }
+
+    /// Updates the project repository and runs the update command for a job.
+    /// A failing update command is reported as `Error::CommandExit`.
+    pub fn execute(&self, job: &Job) -> Result<()> {
+        let project = try!(self.get_project(&job.project));
+        let dry_run = self.conf.dry_run || project.dry_run;
+        let requested = job.revision.as_ref().or(job.commit.as_ref());
+        let start = Instant::now();
+        // Waiting for the lock and setting up git fail the job like the update itself
+        let fail = |err| self.finish(project, job, dry_run, requested.cloned(), start, Err(err));
+        // Held until the job is finished, so that concurrent jobs from
+        // other koukku instances can't update the same working tree
+        let _lock = match lock::acquire(&self.conf.location,
+                                        &project.id,
+                                        project.on_locked,
+                                        requested.map(|c| &c[..]),
+                                        project.lock_timeout) {
+            Ok(Some(lock)) => lock,
+            Ok(None) => {
+                info!("Project {} is being updated to the same commit by another process. \
+                       Skipping job.",
+                      project.id);
+                self.status.metrics.job(&project.id, job.trigger.name(), "skipped");
+                return Ok(());
+            }
+            Err(err) => return fail(err),
+        };
+        let backend = match git::backend(self.conf.git_backend,
+                                         &self.conf.gitpath,
+                                         dry_run,
+                                         &project.exec) {
+            Ok(backend) => backend,
+            Err(err) => return fail(err),
+        };
+        let git = &*backend;
+        // Dry runs are not reported to GitHub
+        let mut reporter = if dry_run {
+            None
+        } else {
+            Reporter::new(&self.conf.github_api, project)
+        };
+        if let (Some(r), Some(sha)) = (reporter.as_mut(), requested) {
+            log_report(&project.id, r.pending(sha));
+        }
+
+        let result = update_project(&self.conf.location,
+                                    git,
+                                    project,
+                                    job,
+                                    self.output,
+                                    &self.status.metrics);
+        let path = repo_path(&self.conf.location, project);
+        let commit = git_head(git, &path).or(requested.cloned());
+        if let (Some(r), Some(sha)) = (reporter.as_mut(), commit.as_ref().or(requested)) {
+            log_report(&project.id, r.finish(sha, result.is_ok()));
+        }
+        self.finish(project, job, dry_run, commit, start, result)
+    }
+
+    // Records the outcome of a job that wasn't skipped, runs the failure
+    // handler and sends the notification
+    fn finish(&self,
+              project: &Project,
+              job: &Job,
+              dry_run: bool,
+              commit: Option<String>,
+              start: Instant,
+              result: Result<Option<String>>)
+              -> Result<()> {
+        let outcome = match result {
+            Ok(_) if dry_run => "dry_run",
+            Ok(_) => history::SUCCESS,
+            Err(_) => "failure",
+        };
+        self.status.metrics.job(&project.id, job.trigger.name(), outcome);
+        self.record_history(project, job, commit.clone(), outcome);
+
+        // Only deploys that ran the update command or failed are notified
+        let output = match result {
+            Ok(Some(ref output)) => Some(output.to_owned()),
+            Err(ref err) if !dry_run => {
+                self.run_on_failure(project, err);
+                Some(err.output())
+            }
+            _ => None,
+        };
+        if let (Some(notify), Some(output)) = (project.notify.as_ref(), output) {
+            notify::send(notify,
+                         &Deploy {
+                             project: project,
+                             job: &job.id,
+                             branch: job.branch.as_ref().unwrap_or(&project.branch),
+                             commit: commit.as_ref().map(|c| &c[..]),
+                             outcome: outcome,
+                             duration: start.elapsed(),
+                             output: &output,
+                         });
+        }
+        result.map(|_| ())
+    }
+
+    // The failure handler gets the reason in KOUKKU_FAILURE and the error
+    // code in KOUKKU_FAILURE_CODE. Its own failure is only logged.
+    fn run_on_failure(&self, project: &Project, err: &Error) {
+        if let Some(ref command) = project.on_failure {
+            let root = Path::new(&self.conf.location).join(&project.id);
+            let dir = if root.exists() {
+                root
+            } else {
+                PathBuf::from(&self.conf.location)
+            };
+            let reason = err.to_string();
+            let env = [("KOUKKU_FAILURE", &reason[..]), ("KOUKKU_FAILURE_CODE", err.code())];
+            let result = run_hook("on_failure",
+                                  Some(command),
+                                  &dir,
+                                  self.output,
+                                  &project.exec,
+                                  &env);
+            if let Err(hook_err) = result {
+                warn!("Failure handler of project {} failed: {}",
+                      project.id,
+                      hook_err);
+            }
         }
     }
 
-    fn update_repo(&self, repo: &str) -> Result<()> {
-        let project = try!(self.get_project(repo));
-        update_project(&self.conf.location, &self.conf.gitpath, project)
+    // Failing to write the history must not fail the deploy itself
+    fn record_history(&self, project: &Project, job: &Job, commit: Option<String>, outcome: &str) {
+        let entry = Entry::new(&job.id, commit, outcome, job.trigger.name());
+        if let Err(err) = self.history.record(&project.id, entry) {
+            warn!("Failed to record deploy history for project {}: {}",
+                  project.id,
+                  err);
+        }
     }
 
-    fn get_project(&self, repo: &str) -> Result<&Project> {
+    fn get_project(&self, id: &str) -> Result<&Project> {
         self.conf
-            .get_project(repo)
-            .ok_or(Error::app(Reason::InvalidRepository, "No repository found"))
+            .get_project(id)
+            .ok_or(Error::app(Reason::InvalidRepository, "No project found"))
+    }
+}
+
+fn log_report(id: &str, result: Result<()>) {
+    if let Err(err) = result {
+        warn!("Failed to report deploy of project {} to GitHub: {}", id, err);
     }
 }
 
-fn update_project(location: &str, git: &str, project: &Project) -> Result<()> {
-    let path_buf = Path::new(location).join(&project.id);
+// Returns the output of the update command, or None if it wasn't run
+fn update_project(location: &str,
+                  git: &GitBackend,
+                  project: &Project,
+                  job: &Job,
+                  output_mode: OutputMode,
+                  metrics: &Metrics)
+                  -> Result<Option<String>> {
+    let path_buf = repo_path(location, project);
     let path = path_buf.as_path();
+    let branch = job.branch.as_ref().unwrap_or(&project.branch);
+    try!(git::check_revision(branch));
+    if let Some(ref revision) = job.revision {
+        try!(git::check_revision(revision));
+    }
 
-    let has_changed = try!(update_repo(git, &path, project));
+    let opts = &project.git;
+    let has_changed = try!(update_repo(git, &path, &project.repo, branch, opts, metrics));
+    if let Some(ref revision) = job.revision {
+        let _ = try!(timed(metrics, "checkout", || checkout_revision(git, path, revision, opts)));
+        try!(update_worktree(git, path, opts, metrics));
+    }
 
-    if has_changed {
-        let _ = try!(run_from_str(&project.command, path));
-        info!("Repository {} updated successfully", &project.repo);
-        Ok(())
+    if let Some(keep) = project.releases {
+        let root = Path::new(location).join(&project.id);
+        let release = Release {
+            git: git,
+            project: project,
+            job: job,
+            keep: keep,
+            output_mode: output_mode,
+            metrics: metrics,
+        };
+        deploy_release(&release, &root, path)
+    } else if (has_changed || job.is_forced()) && git.dry_run() {
+        info!("Dry run: would run update command {} in {}",
+              project.command,
+              path_str(path));
+        Ok(None)
+    } else if has_changed || job.is_forced() {
+        let output = try!(run_command(project, job, path, output_mode, metrics));
+        try!(finish_deploy(project, job, path, output_mode, metrics));
+        Ok(Some(output))
     } else {
         info!("No changes in repository. Skipping update command.");
-        Ok(())
+        Ok(None)
+    }
+}
+
+// Projects using release directories keep the repository in a subdirectory
+fn repo_path(location: &str, project: &Project) -> PathBuf {
+    let root = Path::new(location).join(&project.id);
+    match project.releases {
+        Some(_) => root.join(REPO_DIR),
+        None => root,
     }
 }
 
-fn update_repo(git: &str, path: &Path, project: &Project) -> Result<bool> {
-    if path.exists() {
-        info!("Local repo exists: updating");
-        let _ = try!(git_checkout(git, path, &project.branch));
-        let _ = try!(git_remote_update(git, path));
-        let has_changed = try!(git_remote_changed(git, path));
-        let _ = try!(git_pull(git, path));
-        Ok(has_changed)
+// Runs the pre_command and the update command, and returns the command output.
+// Both get the context of the job in KOUKKU_* environment variables.
+fn run_command(project: &Project,
+               job: &Job,
+               path: &Path,
+               output_mode: OutputMode,
+               metrics: &Metrics)
+               -> Result<String> {
+    let env = job_env(project, job);
+    try!(run_hook("pre_command",
+                  project.pre_command.as_ref(),
+                  path,
+                  output_mode,
+                  &project.exec,
+                  &env));
+    info!("Running update command {} in {}", project.command, path_str(path));
+    let start = Instant::now();
+    let output = try!(match project.exec.image {
+        Some(ref image) => {
+            run_in_container(image, &project.command, path, output_mode, &project.exec, &env)
+        }
+        None => run_from_str(&project.command, path, output_mode, &project.exec, &env),
+    });
+    metrics.command(&project.id, start.elapsed(), output.status.code());
+    try!(check_exit(&project.command, &output));
+    Ok(output_text(&output))
+}
+
+// The context of the update given to pre_command, command and post_command
+fn job_env<'a>(project: &'a Project, job: &'a Job) -> [(&'static str, &'a str); 5] {
+    let branch = job.branch.as_ref().unwrap_or(&project.branch);
+    let commit = job.revision.as_ref().or(job.commit.as_ref()).map(|c| &c[..]).unwrap_or("");
+    [("KOUKKU_PROJECT", &project.id[..]),
+     ("KOUKKU_JOB", &job.id[..]),
+     ("KOUKKU_TRIGGER", job.trigger.name()),
+     ("KOUKKU_BRANCH", &branch[..]),
+     ("KOUKKU_COMMIT", commit)]
+}
+
+// Runs the post_command once the update is in place
+fn finish_deploy(project: &Project,
+                 job: &Job,
+                 path: &Path,
+                 output_mode: OutputMode,
+                 metrics: &Metrics)
+                 -> Result<()> {
+    try!(run_hook("post_command",
+                  project.post_command.as_ref(),
+                  path,
+                  output_mode,
+                  &project.exec,
+                  &job_env(project, job)));
+    metrics.deploy_succeeded(&project.id);
+    info!("Repository {} updated successfully", &project.repo);
+    Ok(())
+}
+
+fn run_hook(stage: &str,
+            command: Option<&String>,
+            path: &Path,
+            output_mode: OutputMode,
+            opts: &ExecOptions,
+            env: &[(&str, &str)])
+            -> Result<()> {
+    match command {
+        Some(command) => {
+            info!("Running {} {} in {}", stage, command, path_str(path));
+            let output = try!(run_from_str(command, path, output_mode, opts, env));
+            check_exit(command, &output)
+        }
+        None => Ok(()),
+    }
+}
+
+fn check_exit(command: &str, output: &Output) -> Result<()> {
+    if output.status.success() {
+        Ok(())
     } else {
+        let code = output.status.code().unwrap_or(1);
+        Err(Error::CommandExit(failure_message(command, output), code, output_text(output)))
+    }
+}
+
+fn output_text(output: &Output) -> String {
+    format!("{}{}",
+            String::from_utf8_lossy(&output.stdout),
+            String::from_utf8_lossy(&output.stderr))
+}
+
+/// A deploy of a project into a release directory
+struct Release<'a> {
+    git: &'a GitBackend,
+    project: &'a Project,
+    job: &'a Job,
+    /// Number of releases to keep
+    keep: usize,
+    output_mode: OutputMode,
+    metrics: &'a Metrics,
+}
+
+// Checks out the repository HEAD to a new release directory and runs the
+// update command there. The `current` link is switched to the release only
+// after the command succeeds, so a failed deploy never touches the live release.
+// Deploying the live commit again also builds a new release.
+fn deploy_release(deploy: &Release, root: &Path, repo: &Path) -> Result<Option<String>> {
+    let (git, project) = (deploy.git, deploy.project);
+    if git.dry_run() {
+        info!("Dry run: would deploy a new release in {} and run update command {}",
+              path_str(root),
+              project.command);
+        return Ok(None);
+    }
+    let sha = try!(git_head(git, repo)
+                       .ok_or(Error::app(Reason::CommandFailed, "Could not resolve HEAD")));
+    let live = fs::read_link(root.join(CURRENT_LINK)).ok();
+    let live_commit = live.as_ref()
+                          .and_then(|target| target.file_name())
+                          .and_then(|name| name.to_str())
+                          .map(|name| parse_release(name).1);
+    if live_commit == Some(&sha[..]) && !deploy.job.is_forced() {
+        info!("Release {} is already live. Skipping update command.", sha);
+        return Ok(None);
+    }
+
+    let target = Path::new(RELEASES_DIR).join(release_name(&sha));
+    let release = root.join(&target);
+    try!(fs::create_dir_all(root.join(RELEASES_DIR)));
+    // A release that never went live would take up one of the kept slots
+    let output = match build_release(deploy, repo, root, &target, &sha) {
+        Ok(output) => output,
+        Err(err) => {
+            if let Err(err) = fs::remove_dir_all(&release) {
+                warn!("Failed to remove release {}: {}", path_str(&release), err);
+            }
+            return Err(err);
+        }
+    };
+    info!("Release {} is now live", sha);
+    if let Err(err) = prune_releases(&root.join(RELEASES_DIR), &release, deploy.keep) {
+        warn!("Failed to remove old releases of project {}: {}", project.id, err);
+    }
+    // The release is live at this point, so the deploy has succeeded even if
+    // post_command fails. The failure is logged and added to the output.
+    match finish_deploy(project, deploy.job, &release, deploy.output_mode, deploy.metrics) {
+        Ok(_) => Ok(Some(output)),
+        Err(err) => {
+            warn!("post_command of project {} failed after release {} went live: {}",
+                  project.id,
+                  sha,
+                  err);
+            deploy.metrics.deploy_succeeded(&project.id);
+            Ok(Some(format!("{}\npost_command failed:\n{}", output, err.output())))
+        }
+    }
+}
+
+// Releases are named `<creation time in nanoseconds>-<sha>`, so that
+// they can be ordered by creation and a commit can be deployed again
+fn release_name(sha: &str) -> String {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or(Duration::from_secs(0));
+    format!("{}{:09}-{}", now.as_secs(), now.subsec_nanos(), sha)
+}
+
+// Returns the creation time and the commit of a release. Releases named
+// by the commit only are from older versions, and count as the oldest.
+fn parse_release(name: &str) -> (u64, &str) {
+    let mut parts = name.splitn(2, '-');
+    match (parts.next().and_then(|time| time.parse().ok()), parts.next()) {
+        (Some(time), Some(sha)) => (time, sha),
+        _ => (0, name),
+    }
+}
+
+// Builds the release and makes it the live one
+fn build_release(deploy: &Release,
+                 repo: &Path,
+                 root: &Path,
+                 target: &Path,
+                 sha: &str)
+                 -> Result<String> {
+    let (git, project) = (deploy.git, deploy.project);
+    let release = root.join(target);
+    let _ = try!(timed(deploy.metrics, "release", || {
+        git_release(git, repo, &release, sha, &project.git)
+    }));
+    try!(update_worktree(git, &release, &project.git, deploy.metrics));
+    let output = try!(run_command(project,
+                                  deploy.job,
+                                  &release,
+                                  deploy.output_mode,
+                                  deploy.metrics));
+    try!(switch_current(root, target));
+    Ok(output)
+}
+
+// Renaming a link over another one replaces it atomically
+fn switch_current(root: &Path, target: &Path) -> Result<()> {
+    let tmp = root.join(format!("{}.tmp", CURRENT_LINK));
+    if fs::symlink_metadata(&tmp).is_ok() {
+        try!(fs::remove_file(&tmp));
+    }
+    try!(unix::fs::symlink(target, &tmp));
+    fs::rename(&tmp, root.join(CURRENT_LINK)).map_err(Error::from)
+}
+
+// Keeps the most recently created releases, and never removes the live one
+fn prune_releases(releases: &Path, live: &Path, keep: usize) -> Result<()> {
+    let mut dirs = Vec::new();
+    for entry in try!(fs::read_dir(releases)) {
+        let path = try!(entry).path();
+        let created = path.file_name()
+                          .and_then(|name| name.to_str())
+                          .map_or(0, |name| parse_release(name).0);
+        dirs.push((created, path));
+    }
+    dirs.sort_by(|a, b| b.0.cmp(&a.0));
+    for (_, dir) in dirs.into_iter().skip(keep) {
+        if dir != live {
+            info!("Removing old release {}", path_str(&dir));
+            try!(fs::remove_dir_all(&dir));
+        }
+    }
+    Ok(())
+}
+
+fn update_repo(git: &GitBackend,
+               path: &Path,
+               repo: &str,
+               branch: &str,
+               opts: &GitOptions,
+               metrics: &Metrics)
+               -> Result<bool> {
+    // A directory left behind by a failed clone is cloned into again
+    if !path.join(".git").exists() {
         info!("No local repo found: cloning");
-        let _ = try!(git_clone(git, path, &project.repo));
-        let _ = try!(git_checkout(git, path, &project.branch));
-        Ok(true)
+        let _ = try!(timed(metrics, "clone", || git_clone(git, path, repo, opts)));
+        let _ = try!(timed(metrics, "sparse_checkout", || git_sparse_checkout(git, path, opts)));
+        let _ = try!(timed(metrics, "checkout", || git_checkout(git, path, branch)));
+        try!(update_worktree(git, path, opts, metrics));
+        return Ok(true);
+    }
+
+    info!("Local repo exists: updating");
+    if opts.on_dirty == DirtyPolicy::Fail {
+        return pull_repo(git, path, branch, opts, metrics);
+    }
+    if let Some(problem) = check_dirty(git, path, repo, opts) {
+        warn!("Repository in {} is dirty: {}", path_str(path), problem);
+        try!(recover_repo(git, path, repo, branch, opts, metrics));
+        return Ok(true);
+    }
+    match pull_repo(git, path, branch, opts, metrics) {
+        // Network errors and the like fail the update without touching the repository
+        Err(err) => {
+            match check_dirty(git, path, repo, opts).or_else(|| check_diverged(git, path)) {
+                Some(problem) => {
+                    warn!("Failed to update repository in {}: {}: {}",
+                          path_str(path),
+                          problem,
+                          err);
+                    try!(recover_repo(git, path, repo, branch, opts, metrics));
+                    Ok(true)
+                }
+                None => Err(err),
+            }
+        }
+        result => result,
     }
 }
 
-fn git_clone(git: &str, path: &Path, project: &str) -> BytesResult {
-    let path_s = try!(path.to_str().ok_or(Error::app(Reason::InvalidPath, "Invalid project path")));
-    info!("Cloning project {} to {}", project, path_s);
-    run(Command::new(git)
-            .arg("clone")
-            .arg(github_url(project))
-            .arg(path_s),
-        "git clone")
+// The repository has changed if HEAD moved, which includes coming back to
+// the branch from a revision checked out by a rollback
+fn pull_repo(git: &GitBackend,
+             path: &Path,
+             branch: &str,
+             opts: &GitOptions,
+             metrics: &Metrics)
+             -> Result<bool> {
+    let before = try!(timed(metrics, "rev_parse", || Ok(git_head(git, path))));
+    let _ = try!(timed(metrics, "sparse_checkout", || git_sparse_checkout(git, path, opts)));
+    let _ = try!(timed(metrics, "checkout", || git_checkout(git, path, branch)));
+    let _ = try!(timed(metrics, "remote_update", || git_remote_update(git, path, opts)));
+    let _ = try!(timed(metrics, "pull", || git_pull(git, path, branch, opts)));
+    try!(update_worktree(git, path, opts, metrics));
+    let after = try!(timed(metrics, "rev_parse", || Ok(git_head(git, path))));
+    Ok(git.dry_run() || before.is_none() || before != after)
 }
 
-fn github_url(project: &str) -> String {
-    format!("https://github.com/{}.git", project)
+// Local modifications, a corrupted index, or a remote pointing elsewhere
+// make the repository dirty. Untracked files, such as build output, don't.
+fn check_dirty(git: &GitBackend, path: &Path, repo: &str, opts: &GitOptions) -> Option<String> {
+    if git.dry_run() {
+        return None;
+    }
+    match git.remote_url(path) {
+        Ok(ref url) if *url != github_url(repo, opts) => {
+            return Some(format!("remote origin points to {}", url));
+        }
+        Err(err) => return Some(err.to_string()),
+        Ok(_) => (),
+    }
+    match git.has_local_changes(path) {
+        Ok(true) => Some("local modifications found".to_owned()),
+        Ok(false) => None,
+        Err(err) => Some(err.to_string()),
+    }
+}
+
+fn check_diverged(git: &GitBackend, path: &Path) -> Option<String> {
+    match git.diverged(path) {
+        Ok(true) => Some("branch has diverged from the remote".to_owned()),
+        Ok(false) => None,
+        Err(err) => Some(err.to_string()),
+    }
 }
 
-fn git_checkout(git: &str, path: &Path, branch: &str) -> BytesResult {
-    info!("Checking out branch {} in {}", branch, path_str(path));
-    run(Command::new(git)
-            .arg("checkout")
-            .arg(branch)
-            .current_dir(path),
-        "git checkout")
+fn recover_repo(git: &GitBackend,
+                path: &Path,
+                repo: &str,
+                branch: &str,
+                opts: &GitOptions,
+                metrics: &Metrics)
+                -> Result<()> {
+    warn!("Recovering repository in {} with on_dirty = {}",
+          path_str(path),
+          opts.on_dirty.name());
+    let result = match opts.on_dirty {
+        DirtyPolicy::Fail => Ok(()),
+        DirtyPolicy::Reset => timed(metrics, "reset", || reset_repo(git, path, repo, branch, opts)),
+        DirtyPolicy::Reclone => {
+            timed(metrics, "reclone", || reclone_repo(git, path, repo, branch, opts))
+        }
+    };
+    match result {
+        Ok(_) => warn!("Recovered repository in {}", path_str(path)),
+        Err(ref err) => error!("Failed to recover repository in {}: {}", path_str(path), err),
+    }
+    try!(result);
+    update_worktree(git, path, opts, metrics)
+}
+
+// Throws away local changes and resets the branch to match the remote
+fn reset_repo(git: &GitBackend,
+              path: &Path,
+              repo: &str,
+              branch: &str,
+              opts: &GitOptions)
+              -> Result<()> {
+    info!("Resetting {} to origin/{}", path_str(path), branch);
+    git.reset(path, &github_url(repo, opts), branch, opts)
+}
+
+// Clones the repository next to the broken one, and swaps it in only
+// after the clone succeeds
+fn reclone_repo(git: &GitBackend,
+                path: &Path,
+                repo: &str,
+                branch: &str,
+                opts: &GitOptions)
+                -> Result<()> {
+    let fresh = sibling_path(path, "reclone");
+    let old = sibling_path(path, "old");
+    if !git.dry_run() && fresh.exists() {
+        try!(fs::remove_dir_all(&fresh));
+    }
+    let _ = try!(git_clone(git, &fresh, repo, opts));
+    let _ = try!(git_sparse_checkout(git, &fresh, opts));
+    let _ = try!(git_checkout(git, &fresh, branch));
+    if git.dry_run() {
+        info!("Dry run: would replace {} with {}",
+              path_str(path),
+              path_str(&fresh));
+        return Ok(());
+    }
+    if old.exists() {
+        try!(fs::remove_dir_all(&old));
+    }
+    info!("Replacing {} with a fresh clone", path_str(path));
+    try!(fs::rename(path, &old));
+    try!(fs::rename(&fresh, path));
+    fs::remove_dir_all(&old).map_err(Error::from)
+}
+
+fn sibling_path(path: &Path, suffix: &str) -> PathBuf {
+    let name = path.file_name().and_then(|n| n.to_str()).unwrap_or("repo");
+    path.with_file_name(format!("{}.{}", name, suffix))
+}
+
+// Brings submodules and LFS files up to date with the checked out commit
+fn update_worktree(git: &GitBackend,
+                   path: &Path,
+                   opts: &GitOptions,
+                   metrics: &Metrics)
+                   -> Result<()> {
+    if opts.submodules {
+        let _ = try!(timed(metrics, "submodules", || git_submodule_update(git, path, opts)));
+    }
+    if opts.lfs {
+        let _ = try!(timed(metrics, "lfs", || git_lfs_pull(git, path, opts)));
+    }
+    Ok(())
+}
+
+fn timed<T, F: FnOnce() -> Result<T>>(metrics: &Metrics, step: &str, f: F) -> Result<T> {
+    let start = Instant::now();
+    let result = f();
+    metrics.git_step(step, start.elapsed());
+    result
+}
+
+fn git_clone(git: &GitBackend, path: &Path, project: &str, opts: &GitOptions) -> Result<()> {
+    info!("Cloning project {} to {}", project, path_str(path));
+    git.clone_repo(&github_url(project, opts), path, opts)
+}
+
+fn git_sparse_checkout(git: &GitBackend, path: &Path, opts: &GitOptions) -> Result<()> {
+    if !opts.sparse_paths.is_empty() {
+        info!("Setting sparse checkout paths in {}", path_str(path));
+    }
+    git.sparse_checkout(path, opts)
+}
+
+fn git_submodule_update(git: &GitBackend, path: &Path, opts: &GitOptions) -> Result<()> {
+    info!("Updating submodules in {}", path_str(path));
+    git.update_submodules(path, opts)
+}
+
+fn git_lfs_pull(git: &GitBackend, path: &Path, opts: &GitOptions) -> Result<()> {
+    info!("Pulling LFS files in {}", path_str(path));
+    git.lfs_pull(path, opts)
+}
+
+// Projects with an SSH key are cloned over SSH
+fn github_url(project: &str, opts: &GitOptions) -> String {
+    if opts.ssh_key.is_some() {
+        format!("git@github.com:{}.git", project)
+    } else {
+        format!("https://github.com/{}.git", project)
+    }
+}
+
+fn git_checkout(git: &GitBackend, path: &Path, branch: &str) -> Result<()> {
+    info!("Checking out {} in {}", branch, path_str(path));
+    git.checkout(path, branch)
+}
+
+// Revisions older than a shallow clone, such as rollback targets,
+// are checked out after fetching the full history
+fn checkout_revision(git: &GitBackend, path: &Path, rev: &str, opts: &GitOptions) -> Result<()> {
+    match git_checkout(git, path, rev) {
+        Err(ref err) if opts.depth.is_some() => {
+            warn!("Failed to check out {}, fetching full history: {}", rev, err);
+            try!(git.deepen(path, opts));
+            git_checkout(git, path, rev).map_err(|err| {
+                let msg = format!("Revision {} not found in the repository: {}", rev, err);
+                Error::app(Reason::MissingRevision, msg)
+            })
+        }
+        result => result,
+    }
+}
+
+fn git_release(git: &GitBackend,
+               repo: &Path,
+               release: &Path,
+               sha: &str,
+               opts: &GitOptions)
+               -> Result<()> {
+    info!("Creating release {} in {}", sha, path_str(release));
+    git.create_release(repo, release, sha, opts)
 }
 
 fn path_str(path: &Path) -> &str {
     path.to_str().unwrap_or("[unprintable path]")
 }
 
-fn git_remote_update(git: &str, path: &Path) -> BytesResult {
+fn git_remote_update(git: &GitBackend, path: &Path, opts: &GitOptions) -> Result<()> {
     info!("Updating remotes in {}", path_str(path));
-    run(Command::new(git)
-            .arg("remote")
-            .arg("update")
-            .current_dir(path),
-        "git remote update")
+    git.fetch(path, opts)
 }
 
-fn git_pull(git: &str, path: &Path) -> BytesResult {
+fn git_pull(git: &GitBackend, path: &Path, branch: &str, opts: &GitOptions) -> Result<()> {
     info!("Pulling changes in {}", path_str(path));
-    run(Command::new(git).arg("pull").current_dir(path), "git pull")
+    git.pull(path, branch, opts)
 }
 
-fn git_remote_changed(git: &str, path: &Path) -> Result<bool> {
-    let local = try!(run(Command::new(git)
-                             .arg("rev-parse")
-                             .arg("@")
-                             .current_dir(path),
-                         "git rev-parse"));
-    let remote = try!(run(Command::new(git)
-                              .arg("rev-parse")
-                              .arg("@{u}")
-                              .current_dir(path),
-                          "git rev-parse"));
-    Ok(local != remote)
+fn git_head(git: &GitBackend, path: &Path) -> Option<String> {
+    if path.join(".git").exists() {
+        git.head(path).ok()
+    } else {
+        None
+    }
 }
 
-fn run_from_str(command: &str, path: &Path) -> BytesResult {
-    info!("Running update command {} in {}", command, path_str(path));
-    run(Command::new(command).current_dir(path), command)
+fn run_from_str(command: &str,
+                path: &Path,
+                output_mode: OutputMode,
+                opts: &ExecOptions,
+                env: &[(&str, &str)])
+                -> Result<Output> {
+    let mut cmd = try!(new_command(command, path, opts));
+    try!(privileges::configure(&mut cmd, opts));
+    collect_output(cmd, path, output_mode, env)
 }
 
-fn run(command: &mut Command, name: &str) -> BytesResult {
-    command.stdin(Stdio::null())
-           .output()
-           .map_err(Error::from)
-           .and_then(|out| non_zero_to_error(name, out))
+// Runs the command with the container runtime. The directory is mounted
+// at the same path in the container, and the environment variables are
+// passed by name, so that their values don't show up in the arguments.
+// The runtime itself runs as koukku, and the user and limits of the
+// project are given to the container instead.
+fn run_in_container(image: &str,
+                    command: &str,
+                    path: &Path,
+                    output_mode: OutputMode,
+                    opts: &ExecOptions,
+                    env: &[(&str, &str)])
+                    -> Result<Output> {
+    let dir = try!(fs::canonicalize(path));
+    let dir_str = path_str(&dir);
+    // Fields of --mount are separated by commas, which can't be escaped portably
+    if dir_str.contains(',') {
+        let msg = format!("Path {} can't be mounted in a container", dir_str);
+        return Err(Error::app(Reason::InvalidPath, msg));
+    }
+    let mut cmd = Command::new(&opts.container_runtime);
+    cmd.arg("run")
+       .arg("--rm")
+       .arg("--mount")
+       .arg(format!("type=bind,src={},dst={}", dir_str, dir_str))
+       .arg("--workdir")
+       .arg(dir_str)
+       .args(&try!(container_options(opts)));
+    for &(key, _) in env {
+        cmd.arg("--env").arg(key);
+    }
+    cmd.arg(image).arg(command);
+    collect_output(cmd, path, output_mode, env)
 }
 
-fn non_zero_to_error(cmd: &str, out: Output) -> BytesResult {
-    if out.status.success() {
-        Ok(out.stdout)
+// Without a user, the container runs as the user running koukku instead of
+// the root user of the image. Rootless podman maps that user to itself with keep-id.
+fn container_options(opts: &ExecOptions) -> Result<Vec<String>> {
+    let (uid, gid) = try!(RunAs::lookup(opts)).ids();
+    let euid = unsafe { libc::geteuid() };
+    let mut args = vec![format!("--user={}:{}",
+                                uid.unwrap_or(euid),
+                                gid.unwrap_or_else(|| unsafe { libc::getegid() }))];
+    if euid != 0 && opts.container_runtime.ends_with("podman") {
+        args.push("--userns=keep-id".to_owned());
+    }
+    if let Some(bytes) = opts.memory_limit {
+        args.push(format!("--memory={}", bytes));
+    }
+    if let Some(seconds) = opts.cpu_limit {
+        args.push(format!("--ulimit=cpu={}:{}", seconds, seconds));
+    }
+    if let Some(files) = opts.open_files_limit {
+        args.push(format!("--ulimit=nofile={}:{}", files, files));
+    }
+    Ok(args)
+}
+
+fn collect_output(mut cmd: Command,
+                  path: &Path,
+                  output_mode: OutputMode,
+                  env: &[(&str, &str)])
+                  -> Result<Output> {
+    cmd.current_dir(path).stdin(Stdio::null());
+    for &(key, value) in env {
+        cmd.env(key, value);
+    }
+    if output_mode == OutputMode::Inherit {
+        cmd.stdout(Stdio::inherit()).stderr(Stdio::inherit());
+    }
+    cmd.output().map_err(Error::from)
+}
+
+#[cfg(target_os = "linux")]
+fn new_command(command: &str, path: &Path, opts: &ExecOptions) -> Result<Command> {
+    if opts.sandbox {
+        sandbox::command(command, path, opts)
     } else {
-        Err(output_to_error(cmd, out))
+        Ok(Command::new(command))
     }
 }
 
-fn output_to_error(cmd: &str, out: Output) -> Error {
-    let text = str::from_utf8(&out.stderr).unwrap_or("[invalid string]");
-    let msg = format!("Command {} exited with status {}: {}",
-                      cmd,
-                      out.status,
-                      text);
-    Error::app(Reason::CommandFailed, msg)
+#[cfg(not(target_os = "linux"))]
+fn new_command(command: &str, _: &Path, _: &ExecOptions) -> Result<Command> {
+    Ok(Command::new(command))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Executor, OutputMode, Release, check_exit, deploy_release, parse_release,
+                prune_releases, pull_repo, release_name, run_in_container};
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::{Read, Write};
+    use std::os::unix::fs::PermissionsExt;
+    use std::process::Command;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use ini::Ini;
+    use libc;
+    use conf::{Conf, ExecOptions, GitOptions};
+    use git::{self, BackendKind};
+    use job::{Job, Trigger};
+    use lock::{self, LockPolicy};
+    use metrics::Metrics;
+    use status::Status;
+
+    const FAKE_RUNTIME: &'static str = "#!/bin/sh\necho \"$@\"\necho \"$KOUKKU_PROJECT\"\n";
+
+    #[test]
+    fn runs_command_with_container_runtime() {
+        let dir = env::temp_dir().join("koukku-container-test");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let dir = fs::canonicalize(&dir).unwrap();
+        let runtime = dir.join("fake-runtime");
+        {
+            let mut file = File::create(&runtime).unwrap();
+            file.write_all(FAKE_RUNTIME.as_bytes()).unwrap();
+        }
+        fs::set_permissions(&runtime, fs::Permissions::from_mode(0o755)).unwrap();
+        let opts = ExecOptions {
+            cpu_limit: Some(600),
+            memory_limit: Some(1024 * 1024),
+            open_files_limit: Some(1024),
+            image: Some("alpine".to_owned()),
+            container_runtime: runtime.to_str().unwrap().to_owned(),
+            ..ExecOptions::default()
+        };
+
+        let output = run_in_container("alpine",
+                                      "./deploy.sh",
+                                      &dir,
+                                      OutputMode::Capture,
+                                      &opts,
+                                      &[("KOUKKU_PROJECT", "site")])
+                         .unwrap();
+
+        let dir = dir.to_str().unwrap();
+        let user = unsafe { format!("{}:{}", libc::geteuid(), libc::getegid()) };
+        assert_eq!(String::from_utf8(output.stdout).unwrap(),
+                   format!("run --rm --mount type=bind,src={},dst={} --workdir {} --user={} \
+                            --memory=1048576 --ulimit=cpu=600:600 --ulimit=nofile=1024:1024 \
+                            --env KOUKKU_PROJECT alpine ./deploy.sh\nsite\n",
+                           dir,
+                           dir,
+                           dir,
+                           user));
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn failure_carries_command_output() {
+        let output = Command::new("sh")
+                         .arg("-c")
+                         .arg("echo building; echo broken >&2; exit 3")
+                         .output()
+                         .unwrap();
+
+        let err = check_exit("deploy.sh", &output).unwrap_err();
+
+        assert_eq!(err.output(), "building\nbroken\nExited with status 3");
+    }
+
+    #[test]
+    fn prunes_releases_by_creation_time() {
+        let dir = env::temp_dir().join("koukku-prune-test");
+        let _ = fs::remove_dir_all(&dir);
+        // Created newest first, so their modification times are in the opposite order
+        let names = ["aaa", "100-bbb", "200-ccc", "300-ddd"];
+        for name in names.iter().rev() {
+            fs::create_dir_all(dir.join(name)).unwrap();
+        }
+
+        prune_releases(&dir, &dir.join("100-bbb"), 2).unwrap();
+
+        let mut left = fs::read_dir(&dir)
+                           .unwrap()
+                           .map(|e| e.unwrap().file_name().into_string().unwrap())
+                           .collect::<Vec<_>>();
+        left.sort();
+        assert_eq!(left, vec!["100-bbb", "200-ccc", "300-ddd"]);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn release_names_record_creation() {
+        let first = release_name("abc123");
+        let second = release_name("abc123");
+
+        assert_eq!(parse_release(&first).1, "abc123");
+        assert!(parse_release(&first).0 <= parse_release(&second).0);
+        assert_eq!(parse_release("abc123"), (0, "abc123"));
+    }
+
+    #[test]
+    fn deploys_live_commit_again_and_survives_post_command() {
+        let dir = env::temp_dir().join("koukku-release-test");
+        let _ = fs::remove_dir_all(&dir);
+        let repo = dir.join("repo");
+        fs::create_dir_all(&repo).unwrap();
+        for args in &[vec!["init", "--quiet"],
+                      vec!["-c", "user.name=koukku", "-c", "user.email=koukku@example.com",
+                           "commit", "--quiet", "--allow-empty", "-m", "first"]] {
+            assert!(Command::new("git").current_dir(&repo).args(args).status().unwrap().success());
+        }
+        let conf = format!("location = {}\n[site]\nrepo = Lepovirta/site\nkey = foobar\n\
+                            command = true\npost_command = false\nreleases = 2\n",
+                           dir.display());
+        let conf = Conf::from_ini(&Ini::load_from_str(&conf).unwrap()).unwrap();
+        let project = conf.get_project("site").unwrap();
+        let backend = git::backend(BackendKind::Cli, "git", false, &project.exec).unwrap();
+        let job = Job::new("site", Trigger::Manual);
+        let metrics = Metrics::new();
+        let release = Release {
+            git: &*backend,
+            project: project,
+            job: &job,
+            keep: 2,
+            output_mode: OutputMode::Capture,
+            metrics: &metrics,
+        };
+
+        let output = deploy_release(&release, &dir, &repo).unwrap().unwrap();
+        let first = fs::read_link(dir.join("current")).unwrap();
+        deploy_release(&release, &dir, &repo).unwrap();
+        let second = fs::read_link(dir.join("current")).unwrap();
+
+        assert!(output.contains("post_command failed"));
+        assert!(first != second);
+        assert!(dir.join(&first).exists() && dir.join(&second).exists());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn removes_release_that_fails() {
+        let dir = env::temp_dir().join("koukku-failed-release-test");
+        let _ = fs::remove_dir_all(&dir);
+        let repo = dir.join("repo");
+        fs::create_dir_all(&repo).unwrap();
+        for args in &[vec!["init", "--quiet"],
+                      vec!["-c", "user.name=koukku", "-c", "user.email=koukku@example.com",
+                           "commit", "--quiet", "--allow-empty", "-m", "first"]] {
+            assert!(Command::new("git").current_dir(&repo).args(args).status().unwrap().success());
+        }
+        let conf = format!("location = {}\n[site]\nrepo = Lepovirta/site\nkey = foobar\n\
+                            command = false\nreleases = 2\n",
+                           dir.display());
+        let conf = Conf::from_ini(&Ini::load_from_str(&conf).unwrap()).unwrap();
+        let project = conf.get_project("site").unwrap();
+        let backend = git::backend(BackendKind::Cli, "git", false, &project.exec).unwrap();
+        let job = Job::new("site", Trigger::Manual);
+        let metrics = Metrics::new();
+        let release = Release {
+            git: &*backend,
+            project: project,
+            job: &job,
+            keep: 2,
+            output_mode: OutputMode::Capture,
+            metrics: &metrics,
+        };
+
+        assert!(deploy_release(&release, &dir, &repo).is_err());
+
+        assert_eq!(fs::read_dir(dir.join("releases")).unwrap().count(), 0);
+        assert!(fs::symlink_metadata(dir.join("current")).is_err());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn post_command_gets_job_context() {
+        let dir = env::temp_dir().join("koukku-post-command-test");
+        let _ = fs::remove_dir_all(&dir);
+        let repo = dir.join("repo");
+        fs::create_dir_all(&repo).unwrap();
+        for args in &[vec!["init", "--quiet"],
+                      vec!["-c", "user.name=koukku", "-c", "user.email=koukku@example.com",
+                           "commit", "--quiet", "--allow-empty", "-m", "first"]] {
+            assert!(Command::new("git").current_dir(&repo).args(args).status().unwrap().success());
+        }
+        let script = dir.join("post.sh");
+        File::create(&script)
+            .unwrap()
+            .write_all(b"#!/bin/sh\necho \"$KOUKKU_PROJECT $KOUKKU_TRIGGER\"\nexit 1\n")
+            .unwrap();
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+        let conf = format!("location = {}\n[site]\nrepo = Lepovirta/site\nkey = foobar\n\
+                            command = true\npost_command = {}\nreleases = 2\n",
+                           dir.display(),
+                           script.display());
+        let conf = Conf::from_ini(&Ini::load_from_str(&conf).unwrap()).unwrap();
+        let project = conf.get_project("site").unwrap();
+        let backend = git::backend(BackendKind::Cli, "git", false, &project.exec).unwrap();
+        let job = Job::new("site", Trigger::Manual);
+        let metrics = Metrics::new();
+        let release = Release {
+            git: &*backend,
+            project: project,
+            job: &job,
+            keep: 2,
+            output_mode: OutputMode::Capture,
+            metrics: &metrics,
+        };
+
+        let output = deploy_release(&release, &dir, &repo).unwrap().unwrap();
+
+        assert!(output.contains("site manual"));
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn lock_timeout_runs_failure_handler() {
+        let dir = env::temp_dir().join("koukku-lock-failure-test");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let script = dir.join("on_failure.sh");
+        File::create(&script)
+            .unwrap()
+            .write_all(format!("#!/bin/sh\ntouch '{}'\n", dir.join("failed").display())
+                           .as_bytes())
+            .unwrap();
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+        let conf = format!("location = {}\n[site]\nrepo = Lepovirta/site\nkey = foobar\n\
+                            command = true\non_failure = {}\nlock_timeout = 0\n",
+                           dir.display(),
+                           script.display());
+        let conf = Conf::from_ini(&Ini::load_from_str(&conf).unwrap()).unwrap();
+        let status = Arc::new(Status::new());
+        let executor = Executor::new(conf, status.clone());
+        let _lock = lock::acquire(dir.to_str().unwrap(),
+                                  "site",
+                                  LockPolicy::Wait,
+                                  None,
+                                  Duration::from_secs(1))
+                        .unwrap();
+
+        assert!(executor.execute(&Job::new("site", Trigger::Manual)).is_err());
+
+        assert!(dir.join("failed").exists());
+        assert!(status.render_metrics().contains("outcome=\"failure\""));
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn dry_run_leaves_checkout_alone() {
+        let dir = env::temp_dir().join("koukku-dry-run-test");
+        let _ = fs::remove_dir_all(&dir);
+        let checkout = dir.join("site");
+        fs::create_dir_all(&checkout).unwrap();
+        File::create(checkout.join("index.html")).unwrap().write_all(b"local").unwrap();
+        let script = dir.join("deploy.sh");
+        File::create(&script)
+            .unwrap()
+            .write_all(format!("#!/bin/sh\ntouch '{}'\n", dir.join("ran").display()).as_bytes())
+            .unwrap();
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+        let conf = format!("location = {}\ndry_run = true\n[site]\nrepo = Lepovirta/site\n\
+                            key = foobar\ncommand = {}\non_dirty = reclone\n",
+                           dir.display(),
+                           script.display());
+        let conf = Conf::from_ini(&Ini::load_from_str(&conf).unwrap()).unwrap();
+        let executor = Executor::new(conf, Arc::new(Status::new()));
+
+        executor.execute(&Job::new("site", Trigger::Manual)).unwrap();
+
+        let files = fs::read_dir(&checkout).unwrap().count();
+        let mut content = String::new();
+        File::open(checkout.join("index.html")).unwrap().read_to_string(&mut content).unwrap();
+        assert_eq!((files, &content[..]), (1, "local"));
+        assert!(!dir.join("ran").exists());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn pull_after_rollback_counts_as_change() {
+        let dir = env::temp_dir().join("koukku-pull-test");
+        let _ = fs::remove_dir_all(&dir);
+        let origin = dir.join("origin");
+        fs::create_dir_all(&origin).unwrap();
+        for args in &[vec!["init", "--quiet"],
+                      vec!["-c", "user.name=koukku", "-c", "user.email=koukku@example.com",
+                           "commit", "--quiet", "--allow-empty", "-m", "first"],
+                      vec!["-c", "user.name=koukku", "-c", "user.email=koukku@example.com",
+                           "commit", "--quiet", "--allow-empty", "-m", "second"]] {
+            let status = Command::new("git").current_dir(&origin).args(args).status().unwrap();
+            assert!(status.success());
+        }
+        let branch = Command::new("git")
+                         .current_dir(&origin)
+                         .args(&["rev-parse", "--abbrev-ref", "HEAD"])
+                         .output()
+                         .unwrap();
+        let branch = String::from_utf8(branch.stdout).unwrap();
+        let clone = dir.join("clone");
+        assert!(Command::new("git")
+                    .args(&["clone", "--quiet", origin.to_str().unwrap(), clone.to_str().unwrap()])
+                    .status()
+                    .unwrap()
+                    .success());
+        let exec = ExecOptions::default();
+        let backend = git::backend(BackendKind::Cli, "git", false, &exec).unwrap();
+        let opts = GitOptions::default();
+        let metrics = Metrics::new();
+        backend.checkout(&clone, "HEAD~1").unwrap();
+
+        let rolled_back = pull_repo(&*backend, &clone, branch.trim(), &opts, &metrics).unwrap();
+        let unchanged = pull_repo(&*backend, &clone, branch.trim(), &opts, &metrics).unwrap();
+
+        assert_eq!((rolled_back, unchanged), (true, false));
+        let _ = fs::remove_dir_all(&dir);
+    }
 }
@@ -0,0 +1,730 @@
+use std::fs;
+use std::path::Path;
+use std::process::{Command, Stdio, Output};
+use std::str::{self, FromStr};
+
+use conf::{ExecOptions, GitOptions};
+use error::{Reason, Result, Error};
+use privileges::RunAs;
+
+// Environment variable holding the HTTPS token for the credential helper
+const TOKEN_VAR: &'static str = "KOUKKU_GIT_TOKEN";
+
+// Tokens are only given to the host that projects are cloned from
+const CREDENTIAL_HELPER_KEY: &'static str = "credential.https://github.com.helper";
+
+// The SSH client git runs for projects with an SSH key, looked up in PATH
+const SSH_PROGRAM: &'static str = "ssh";
+
+// Answers only `get` requests, so git never stores or erases the token
+const CREDENTIAL_HELPER: &'static str = "!f() { test \"$1\" = get && \
+                                         echo username=x-access-token && \
+                                         echo \"password=$KOUKKU_GIT_TOKEN\"; }; f";
+
+/// Git implementation used for updating project repositories
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BackendKind {
+    Cli,
+    #[cfg(feature = "libgit2")]
+    Libgit2,
+}
+
+impl FromStr for BackendKind {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> ::std::result::Result<BackendKind, &'static str> {
+        match s {
+            "cli" => Ok(BackendKind::Cli),
+            #[cfg(feature = "libgit2")]
+            "libgit2" => Ok(BackendKind::Libgit2),
+            #[cfg(not(feature = "libgit2"))]
+            "libgit2" => Err("koukku was built without the libgit2 feature"),
+            _ => Err("Git backend must be either cli or libgit2"),
+        }
+    }
+}
+
+impl BackendKind {
+    /// Rejects project options that the backend can't apply
+    #[cfg_attr(not(feature = "libgit2"), allow(unused_variables))]
+    pub fn check_options(&self,
+                         git: &GitOptions,
+                         exec: &ExecOptions)
+                         -> ::std::result::Result<(), &'static str> {
+        match *self {
+            BackendKind::Cli => Ok(()),
+            #[cfg(feature = "libgit2")]
+            BackendKind::Libgit2 => ::libgit::check_options(git, exec),
+        }
+    }
+}
+
+/// Operations needed for keeping a project repository up to date
+pub trait GitBackend {
+    /// Dry-run backends only log what they would do
+    fn dry_run(&self) -> bool {
+        false
+    }
+    fn clone_repo(&self, url: &str, path: &Path, opts: &GitOptions) -> Result<()>;
+    fn sparse_checkout(&self, path: &Path, opts: &GitOptions) -> Result<()>;
+    /// Checks out a branch, creating it from the remote if needed, or any other revision
+    fn checkout(&self, path: &Path, rev: &str) -> Result<()>;
+    fn fetch(&self, path: &Path, opts: &GitOptions) -> Result<()>;
+    /// Whether the current branch can't be fast-forwarded to its upstream
+    fn diverged(&self, path: &Path) -> Result<bool>;
+    fn pull(&self, path: &Path, branch: &str, opts: &GitOptions) -> Result<()>;
+    /// Fetches the full history of a shallow clone
+    fn deepen(&self, path: &Path, opts: &GitOptions) -> Result<()>;
+    fn update_submodules(&self, path: &Path, opts: &GitOptions) -> Result<()>;
+    fn lfs_pull(&self, path: &Path, opts: &GitOptions) -> Result<()>;
+    fn head(&self, path: &Path) -> Result<String>;
+    fn remote_url(&self, path: &Path) -> Result<String>;
+    /// Whether tracked files have been modified. Untracked files are ignored.
+    fn has_local_changes(&self, path: &Path) -> Result<bool>;
+    /// Resets the branch to match the remote, and removes untracked files
+    fn reset(&self, path: &Path, url: &str, branch: &str, opts: &GitOptions) -> Result<()>;
+    /// Checks out a commit of the local repository to a new directory
+    fn create_release(&self,
+                      repo: &Path,
+                      release: &Path,
+                      sha: &str,
+                      opts: &GitOptions)
+                      -> Result<()>;
+}
+
+/// Accepts commit SHAs and valid ref names, which git can't mistake for options
+pub fn check_revision(rev: &str) -> Result<()> {
+    if is_valid_ref(rev) {
+        Ok(())
+    } else {
+        Err(Error::app(Reason::InvalidRevision, format!("Invalid revision {}", rev)))
+    }
+}
+
+// The rules of git check-ref-format, which SHAs also follow
+fn is_valid_ref(rev: &str) -> bool {
+    let valid_part = |part: &str| {
+        !part.is_empty() && !part.starts_with('.') && !part.ends_with(".lock")
+    };
+    let valid_char = |c: char| !c.is_control() && !" ~^:?*[\\".contains(c);
+    !rev.starts_with('-') && rev != "@" && !rev.ends_with('.') && !rev.contains("..") &&
+    !rev.contains("@{") && rev.split('/').all(valid_part) && rev.chars().all(valid_char)
+}
+
+/// The git binary is run as the user and group of the project, so that it never
+/// runs as root in a repository that the update command can write to.
+pub fn backend(kind: BackendKind,
+               gitpath: &str,
+               dry_run: bool,
+               opts: &ExecOptions)
+               -> Result<Box<GitBackend>> {
+    if dry_run {
+        return Ok(Box::new(DryRun));
+    }
+    match kind {
+        BackendKind::Cli => {
+            Ok(Box::new(Cli {
+                binary: gitpath.to_owned(),
+                ssh: SSH_PROGRAM.to_owned(),
+                run_as: try!(RunAs::lookup(opts)),
+            }))
+        }
+        #[cfg(feature = "libgit2")]
+        BackendKind::Libgit2 => Ok(Box::new(::libgit::Libgit2::new())),
+    }
+}
+
+/// Runs the git binary
+pub struct Cli {
+    binary: String,
+    ssh: String,
+    run_as: RunAs,
+}
+
+impl Cli {
+    fn git(&self) -> Command {
+        let mut command = Command::new(&self.binary);
+        self.run_as.apply(&mut command);
+        command
+    }
+
+    fn command(&self, path: &Path) -> Command {
+        let mut command = self.git();
+        command.current_dir(path);
+        command
+    }
+
+    fn output(&self, path: &Path, args: &[&str]) -> Result<String> {
+        let out = try!(run(self.command(path).args(args), &git_name(args)));
+        String::from_utf8(out)
+            .map(|s| s.trim().to_owned())
+            .map_err(|_| Error::app(Reason::CommandFailed, "Invalid output from git"))
+    }
+
+    fn run(&self, path: &Path, args: &[&str]) -> Result<()> {
+        run(self.command(path).args(args), &git_name(args)).map(|_| ())
+    }
+
+    // Commands that talk to the remote
+    fn remote_command(&self, path: &Path, opts: &GitOptions) -> Result<Command> {
+        let mut command = self.command(path);
+        try!(add_credentials(&mut command, &self.ssh, opts));
+        Ok(command)
+    }
+}
+
+impl GitBackend for Cli {
+    fn clone_repo(&self, url: &str, path: &Path, opts: &GitOptions) -> Result<()> {
+        let path_s = try!(path.to_str()
+                              .ok_or(Error::app(Reason::InvalidPath, "Invalid project path")));
+        // The project user may not be able to write to the parent directory
+        let created = !path.exists() && try!(self.run_as.create_dir(path));
+        let mut command = self.git();
+        try!(add_credentials(&mut command, &self.ssh, opts));
+        command.arg("clone");
+        add_fetch_options(&mut command, opts);
+        // Shallow clones would otherwise only track the default branch
+        if opts.depth.is_some() {
+            command.arg("--no-single-branch");
+        }
+        if !opts.sparse_paths.is_empty() {
+            command.arg("--sparse");
+        }
+        let result = run(command.arg(url).arg(path_s), "git clone").map(|_| ());
+        // Git only removes the directory after a failed clone if it created it
+        if result.is_err() && created {
+            let _ = fs::remove_dir_all(path);
+        }
+        result
+    }
+
+    // Sparse paths are set on every update, so that changes to them take effect
+    fn sparse_checkout(&self, path: &Path, opts: &GitOptions) -> Result<()> {
+        if opts.sparse_paths.is_empty() {
+            return Ok(());
+        }
+        run(self.command(path).arg("sparse-checkout").arg("set").args(&opts.sparse_paths),
+            "git sparse-checkout")
+            .map(|_| ())
+    }
+
+    fn checkout(&self, path: &Path, rev: &str) -> Result<()> {
+        self.run(path, &["checkout", rev])
+    }
+
+    fn fetch(&self, path: &Path, opts: &GitOptions) -> Result<()> {
+        let mut command = try!(self.remote_command(path, opts));
+        if opts.depth.is_none() && opts.filter.is_none() {
+            return run(command.arg("remote").arg("update"), "git remote").map(|_| ());
+        }
+        command.arg("fetch").arg("--all");
+        add_fetch_options(&mut command, opts);
+        run(&mut command, "git fetch").map(|_| ())
+    }
+
+    fn diverged(&self, path: &Path) -> Result<bool> {
+        let out = try!(self.command(path)
+                           .args(&["merge-base", "--is-ancestor", "@", "@{u}"])
+                           .stdin(Stdio::null())
+                           .output());
+        match out.status.code() {
+            Some(0) => Ok(false),
+            Some(1) => Ok(true),
+            _ => Err(output_to_error("git merge-base", out)),
+        }
+    }
+
+    fn pull(&self, path: &Path, branch: &str, opts: &GitOptions) -> Result<()> {
+        let mut command = try!(self.remote_command(path, opts));
+        if opts.depth.is_none() {
+            // Diverged branches are left for the on_dirty policy instead of being merged
+            return run(command.arg("pull").arg("--ff-only"), "git pull").map(|_| ());
+        }
+        // The history of a shallow clone may not reach the local commit, so
+        // the branch is reset to the remote instead of being fast-forwarded.
+        // Local changes are still left for the on_dirty policy.
+        if try!(self.has_local_changes(path)) {
+            let msg = format!("Local changes in {}", path.display());
+            return Err(Error::app(Reason::CommandFailed, msg));
+        }
+        command.arg("fetch").arg("origin").arg(branch);
+        add_fetch_options(&mut command, opts);
+        let _ = try!(run(&mut command, "git fetch"));
+        let remote_branch = format!("origin/{}", branch);
+        self.run(path, &["reset", "--hard", &remote_branch[..]])
+    }
+
+    fn deepen(&self, path: &Path, opts: &GitOptions) -> Result<()> {
+        if !path.join(".git").join("shallow").exists() {
+            return Ok(());
+        }
+        let mut command = try!(self.remote_command(path, opts));
+        run(command.arg("fetch").arg("--unshallow").arg("origin"), "git fetch").map(|_| ())
+    }
+
+    fn update_submodules(&self, path: &Path, opts: &GitOptions) -> Result<()> {
+        try!(self.run(path, &["submodule", "sync", "--recursive"]));
+        let mut command = try!(self.remote_command(path, opts));
+        command.arg("submodule").arg("update").arg("--init").arg("--recursive");
+        if let Some(depth) = opts.depth {
+            command.arg("--depth").arg(depth.to_string());
+        }
+        run(&mut command, "git submodule update").map(|_| ())
+    }
+
+    fn lfs_pull(&self, path: &Path, opts: &GitOptions) -> Result<()> {
+        let mut command = try!(self.remote_command(path, opts));
+        run(command.arg("lfs").arg("pull"), "git lfs").map(|_| ())
+    }
+
+    fn head(&self, path: &Path) -> Result<String> {
+        self.output(path, &["rev-parse", "HEAD"])
+    }
+
+    fn remote_url(&self, path: &Path) -> Result<String> {
+        self.output(path, &["config", "--get", "remote.origin.url"])
+    }
+
+    fn has_local_changes(&self, path: &Path) -> Result<bool> {
+        self.output(path, &["status", "--porcelain", "--untracked-files=no"])
+            .map(|changes| !changes.is_empty())
+    }
+
+    fn reset(&self, path: &Path, url: &str, branch: &str, opts: &GitOptions) -> Result<()> {
+        let remote_branch = format!("origin/{}", branch);
+        try!(self.run(path, &["remote", "set-url", "origin", url]));
+        let mut fetch = try!(self.remote_command(path, opts));
+        // Submodules are updated after the reset, and fetching them would read the index
+        fetch.arg("fetch").arg("--no-recurse-submodules").arg("origin");
+        add_fetch_options(&mut fetch, opts);
+        let _ = try!(run(&mut fetch, "git fetch"));
+        // A corrupted index is rebuilt by the hard reset. It's only removed
+        // after fetching, so a failed fetch leaves the repository as it was.
+        let index = path.join(".git").join("index");
+        if index.exists() {
+            info!("Removing index {}", index.display());
+            try!(fs::remove_file(&index));
+        }
+        try!(self.sparse_checkout(path, opts));
+        try!(self.run(path, &["checkout", "--force", "-B", branch, &remote_branch[..]]));
+        try!(self.run(path, &["reset", "--hard", &remote_branch[..]]));
+        self.run(path, &["clean", "--force", "-d"])
+    }
+
+    // Local clones share objects with the repository, so releases are cheap to create
+    fn create_release(&self,
+                      repo: &Path,
+                      release: &Path,
+                      sha: &str,
+                      opts: &GitOptions)
+                      -> Result<()> {
+        let _ = try!(self.run_as.create_dir(release));
+        let _ = try!(run(self.git()
+                             .arg("clone")
+                             .arg("--quiet")
+                             .arg("--no-checkout")
+                             .arg(repo)
+                             .arg(release),
+                         "git clone"));
+        try!(self.sparse_checkout(release, opts));
+        self.run(release, &["checkout", "--quiet", sha])
+    }
+}
+
+/// Logs the operations instead of running them.
+/// The remote is always assumed to have changed.
+pub struct DryRun;
+
+impl GitBackend for DryRun {
+    fn dry_run(&self) -> bool {
+        true
+    }
+
+    fn clone_repo(&self, url: &str, path: &Path, _: &GitOptions) -> Result<()> {
+        info!("Dry run: would clone {} to {}", url, path.display());
+        Ok(())
+    }
+
+    fn sparse_checkout(&self, path: &Path, opts: &GitOptions) -> Result<()> {
+        if !opts.sparse_paths.is_empty() {
+            info!("Dry run: would set sparse checkout paths in {}", path.display());
+        }
+        Ok(())
+    }
+
+    fn checkout(&self, path: &Path, rev: &str) -> Result<()> {
+        info!("Dry run: would check out {} in {}", rev, path.display());
+        Ok(())
+    }
+
+    fn fetch(&self, path: &Path, _: &GitOptions) -> Result<()> {
+        info!("Dry run: would fetch remotes in {}", path.display());
+        Ok(())
+    }
+
+    fn diverged(&self, _: &Path) -> Result<bool> {
+        Ok(false)
+    }
+
+    fn pull(&self, path: &Path, branch: &str, _: &GitOptions) -> Result<()> {
+        info!("Dry run: would pull {} in {}", branch, path.display());
+        Ok(())
+    }
+
+    fn deepen(&self, path: &Path, _: &GitOptions) -> Result<()> {
+        info!("Dry run: would fetch the full history in {}", path.display());
+        Ok(())
+    }
+
+    fn update_submodules(&self, path: &Path, _: &GitOptions) -> Result<()> {
+        info!("Dry run: would update submodules in {}", path.display());
+        Ok(())
+    }
+
+    fn lfs_pull(&self, path: &Path, _: &GitOptions) -> Result<()> {
+        info!("Dry run: would pull LFS files in {}", path.display());
+        Ok(())
+    }
+
+    fn head(&self, _: &Path) -> Result<String> {
+        Err(Error::app(Reason::InvalidRevision, "HEAD is unknown in dry runs"))
+    }
+
+    fn remote_url(&self, _: &Path) -> Result<String> {
+        Err(Error::app(Reason::InvalidRepository, "Remote is unknown in dry runs"))
+    }
+
+    fn has_local_changes(&self, _: &Path) -> Result<bool> {
+        Ok(false)
+    }
+
+    fn reset(&self, path: &Path, _: &str, branch: &str, _: &GitOptions) -> Result<()> {
+        info!("Dry run: would reset {} to origin/{}", path.display(), branch);
+        Ok(())
+    }
+
+    fn create_release(&self, _: &Path, release: &Path, sha: &str, _: &GitOptions) -> Result<()> {
+        info!("Dry run: would check out {} to {}", sha, release.display());
+        Ok(())
+    }
+}
+
+// Shallow and partial clones are kept that way on every fetch
+fn add_fetch_options(command: &mut Command, opts: &GitOptions) {
+    if let Some(depth) = opts.depth {
+        command.arg("--depth").arg(depth.to_string());
+    }
+    if let Some(ref filter) = opts.filter {
+        command.arg(format!("--filter={}", filter));
+    }
+}
+
+// Projects with an SSH key use it and their own known hosts file,
+// regardless of the SSH configuration of the user running koukku.
+// HTTPS tokens are passed to the credential helper in the environment,
+// so they never end up in arguments, remote URLs or error messages.
+fn add_credentials(command: &mut Command, ssh: &str, opts: &GitOptions) -> Result<()> {
+    if let Some(ssh) = ssh_command(ssh, opts) {
+        command.env("GIT_SSH_COMMAND", ssh);
+    }
+    if let Some(ref source) = opts.token {
+        let token = try!(source.read());
+        command.arg("-c")
+               .arg(format!("{}=", CREDENTIAL_HELPER_KEY))
+               .arg("-c")
+               .arg(format!("{}={}", CREDENTIAL_HELPER_KEY, CREDENTIAL_HELPER))
+               .env(TOKEN_VAR, token)
+               .env("GIT_TERMINAL_PROMPT", "0");
+    }
+    Ok(())
+}
+
+fn ssh_command(program: &str, opts: &GitOptions) -> Option<String> {
+    opts.ssh_key.as_ref().map(|key| {
+        let mut ssh = format!("{} -i {} -o IdentitiesOnly=yes",
+                              shell_quote(program),
+                              shell_quote(key));
+        if let Some(ref known_hosts) = opts.known_hosts {
+            ssh.push_str(&format!(" -o UserKnownHostsFile={} -o StrictHostKeyChecking=yes",
+                                  shell_quote(known_hosts)));
+        }
+        ssh
+    })
+}
+
+// GIT_SSH_COMMAND is interpreted by the shell
+fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace("'", "'\\''"))
+}
+
+fn git_name(args: &[&str]) -> String {
+    format!("git {}", args.first().unwrap_or(&""))
+}
+
+fn run(command: &mut Command, name: &str) -> Result<Vec<u8>> {
+    command.stdin(Stdio::null())
+           .output()
+           .map_err(Error::from)
+           .and_then(|out| non_zero_to_error(name, out))
+}
+
+fn non_zero_to_error(cmd: &str, out: Output) -> Result<Vec<u8>> {
+    if out.status.success() {
+        Ok(out.stdout)
+    } else {
+        Err(output_to_error(cmd, out))
+    }
+}
+
+fn output_to_error(cmd: &str, out: Output) -> Error {
+    Error::app(Reason::CommandFailed, failure_message(cmd, &out))
+}
+
+pub fn failure_message(cmd: &str, out: &Output) -> String {
+    let text = str::from_utf8(&out.stderr).unwrap_or("[invalid string]");
+    format!("Command {} exited with status {}: {}",
+            cmd,
+            out.status,
+            text)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::{Read, Write};
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::{Path, PathBuf};
+    use std::process::Command;
+    use super::{Cli, GitBackend, add_credentials, check_revision, shell_quote, ssh_command};
+    use libc;
+    use conf::{ExecOptions, GitOptions, TokenSource};
+    use privileges::RunAs;
+
+    fn options(ssh_key: Option<&str>, known_hosts: Option<&str>) -> GitOptions {
+        GitOptions {
+            ssh_key: ssh_key.map(|k| k.to_owned()),
+            known_hosts: known_hosts.map(|k| k.to_owned()),
+            ..GitOptions::default()
+        }
+    }
+
+    #[test]
+    fn ssh_command_uses_project_key() {
+        assert_eq!(ssh_command("ssh", &options(None, None)), None);
+        assert_eq!(ssh_command("ssh", &options(Some("/keys/deploy"), None)).unwrap(),
+                   "'ssh' -i '/keys/deploy' -o IdentitiesOnly=yes");
+        assert_eq!(ssh_command("ssh", &options(Some("/keys/deploy"), Some("/keys/hosts"))).unwrap(),
+                   "'ssh' -i '/keys/deploy' -o IdentitiesOnly=yes \
+                    -o UserKnownHostsFile='/keys/hosts' -o StrictHostKeyChecking=yes");
+    }
+
+    #[test]
+    fn token_is_not_passed_in_arguments() {
+        env::set_var("KOUKKU_TEST_TOKEN", "s3cr3t\n");
+        let mut opts = options(None, None);
+        opts.token = Some(TokenSource::Env("KOUKKU_TEST_TOKEN".to_owned()));
+        let mut command = Command::new("git");
+
+        add_credentials(&mut command, "ssh", &opts).unwrap();
+
+        let args = command.get_args().map(|arg| arg.to_str().unwrap()).collect::<Vec<_>>();
+        assert_eq!(args[1], "credential.https://github.com.helper=");
+        assert!(args.iter().all(|arg| !arg.contains("s3cr3t")));
+        assert_eq!(opts.token.unwrap().read().unwrap(), "s3cr3t");
+    }
+
+    #[test]
+    fn accepts_only_shas_and_ref_names() {
+        assert!(check_revision("0123abcd").is_ok());
+        assert!(check_revision("release/v1.2").is_ok());
+        assert!(check_revision("--upload-pack=touch").is_err());
+        assert!(check_revision("").is_err());
+        assert!(check_revision("master..evil").is_err());
+        assert!(check_revision("HEAD@{1}").is_err());
+        assert!(check_revision("a b").is_err());
+        assert!(check_revision("refs/.hidden").is_err());
+    }
+
+    #[test]
+    fn shell_quote_escapes_quotes() {
+        assert_eq!(shell_quote("it's"), "'it'\\''s'");
+    }
+
+    // Runs git with a fixed identity and returns its trimmed output
+    fn git(dir: &Path, args: &[&str]) -> String {
+        let out = Command::new("git")
+                      .current_dir(dir)
+                      .args(args)
+                      .env("GIT_AUTHOR_NAME", "koukku")
+                      .env("GIT_AUTHOR_EMAIL", "koukku@example.com")
+                      .env("GIT_COMMITTER_NAME", "koukku")
+                      .env("GIT_COMMITTER_EMAIL", "koukku@example.com")
+                      .output()
+                      .unwrap();
+        assert!(out.status.success(), "git {:?} failed", args);
+        String::from_utf8(out.stdout).unwrap().trim().to_owned()
+    }
+
+    fn commit(dir: &Path, file: &str, content: &str) -> String {
+        File::create(dir.join(file)).unwrap().write_all(content.as_bytes()).unwrap();
+        git(dir, &["add", file]);
+        git(dir, &["commit", "--quiet", "-m", content]);
+        git(dir, &["rev-parse", "HEAD"])
+    }
+
+    // A bare origin with one commit on master, a work repository for
+    // pushing to it, and a clone of it made by the backend
+    fn repositories(name: &str) -> (PathBuf, PathBuf, PathBuf) {
+        let dir = env::temp_dir().join(format!("koukku-git-test-{}", name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let (origin, work, clone) = (dir.join("origin.git"), dir.join("work"), dir.join("clone"));
+        git(&dir, &["init", "--quiet", "--bare", origin.to_str().unwrap()]);
+        git(&origin, &["symbolic-ref", "HEAD", "refs/heads/master"]);
+        git(&dir, &["init", "--quiet", work.to_str().unwrap()]);
+        git(&work, &["remote", "add", "origin", origin.to_str().unwrap()]);
+        commit(&work, "index.html", "first");
+        git(&work, &["push", "--quiet", "origin", "HEAD:refs/heads/master"]);
+        cli().clone_repo(origin.to_str().unwrap(), &clone, &options(None, None)).unwrap();
+        (origin, work, clone)
+    }
+
+    fn cli() -> Cli {
+        Cli {
+            binary: "git".to_owned(),
+            ssh: "ssh".to_owned(),
+            run_as: RunAs::default(),
+        }
+    }
+
+    #[test]
+    fn failed_clone_leaves_no_directory() {
+        let (origin, _, clone) = repositories("failed-clone");
+        let missing = origin.with_file_name("missing.git");
+        let path = clone.with_file_name("failed");
+
+        assert!(cli().clone_repo(missing.to_str().unwrap(), &path, &options(None, None)).is_err());
+        assert!(!path.exists());
+
+        // Only root can create directories for other users
+        if unsafe { libc::geteuid() } != 0 {
+            return;
+        }
+        let exec = ExecOptions { user: Some("nobody".to_owned()), ..ExecOptions::default() };
+        let as_nobody = Cli { run_as: RunAs::lookup(&exec).unwrap(), ..cli() };
+        assert!(as_nobody.clone_repo(missing.to_str().unwrap(), &path, &options(None, None))
+                         .is_err());
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn resets_to_remote_branch() {
+        let (origin, work, clone) = repositories("reset");
+        commit(&clone, "index.html", "local");
+        File::create(clone.join("index.html")).unwrap().write_all(b"modified").unwrap();
+        File::create(clone.join("untracked.txt")).unwrap();
+        File::create(clone.join(".git").join("index")).unwrap().write_all(b"corrupt").unwrap();
+        let remote = commit(&work, "index.html", "second");
+        git(&work, &["push", "--quiet", "origin", "HEAD:refs/heads/master"]);
+
+        cli().reset(&clone, origin.to_str().unwrap(), "master", &options(None, None)).unwrap();
+
+        assert_eq!(cli().head(&clone).unwrap(), remote);
+        assert!(!cli().has_local_changes(&clone).unwrap());
+        assert!(!clone.join("untracked.txt").exists());
+    }
+
+    #[test]
+    fn checks_out_remote_only_branch() {
+        let (_, work, clone) = repositories("checkout");
+        git(&work, &["checkout", "--quiet", "-b", "release"]);
+        let release = commit(&work, "index.html", "release");
+        git(&work, &["push", "--quiet", "origin", "release"]);
+
+        cli().fetch(&clone, &options(None, None)).unwrap();
+        cli().checkout(&clone, "release").unwrap();
+        assert!(!cli().diverged(&clone).unwrap());
+
+        assert_eq!(cli().head(&clone).unwrap(), release);
+        assert_eq!(git(&clone, &["rev-parse", "--abbrev-ref", "@{u}"]), "origin/release");
+    }
+
+    #[test]
+    fn refuses_diverged_pull() {
+        let (_, work, clone) = repositories("diverged");
+        let local = commit(&clone, "index.html", "local");
+        commit(&work, "index.html", "second");
+        git(&work, &["push", "--quiet", "origin", "HEAD:refs/heads/master"]);
+
+        cli().fetch(&clone, &options(None, None)).unwrap();
+
+        assert!(cli().diverged(&clone).unwrap());
+        assert!(cli().pull(&clone, "master", &options(None, None)).is_err());
+        assert_eq!(cli().head(&clone).unwrap(), local);
+    }
+
+    #[test]
+    fn updates_shallow_clone() {
+        let (origin, work, _) = repositories("shallow");
+        let first = git(&work, &["rev-parse", "HEAD"]);
+        commit(&work, "index.html", "second");
+        git(&work, &["push", "--quiet", "origin", "HEAD:refs/heads/master"]);
+        git(&work, &["push", "--quiet", "origin", "HEAD:refs/heads/release"]);
+        let mut opts = options(None, None);
+        opts.depth = Some(1);
+        // Local paths are always cloned in full
+        let url = format!("file://{}", origin.display());
+        let shallow = work.with_file_name("shallow");
+        cli().clone_repo(&url, &shallow, &opts).unwrap();
+
+        cli().checkout(&shallow, "release").unwrap();
+        cli().checkout(&shallow, "master").unwrap();
+        commit(&work, "index.html", "third");
+        let remote = commit(&work, "index.html", "fourth");
+        git(&work, &["push", "--quiet", "origin", "HEAD:refs/heads/master"]);
+        cli().pull(&shallow, "master", &opts).unwrap();
+        assert_eq!(cli().head(&shallow).unwrap(), remote);
+
+        assert!(cli().checkout(&shallow, &first).is_err());
+        cli().deepen(&shallow, &opts).unwrap();
+        cli().checkout(&shallow, &first).unwrap();
+    }
+
+    // The fake ssh records its arguments and fails, like an unreachable host
+    #[test]
+    fn ssh_key_is_used_for_remote_commands() {
+        let (_, _, clone) = repositories("ssh");
+        let ssh = clone.with_file_name("ssh");
+        let log = clone.with_file_name("ssh.log");
+        File::create(&ssh)
+            .unwrap()
+            .write_all(format!("#!/bin/sh\necho \"$@\" >> '{}'\nexit 1\n", log.display())
+                           .as_bytes())
+            .unwrap();
+        fs::set_permissions(&ssh, fs::Permissions::from_mode(0o755)).unwrap();
+        let cli = || Cli { ssh: ssh.to_str().unwrap().to_owned(), ..cli() };
+        let url = "ssh://git@example.invalid/repo.git";
+        let opts = options(Some("/keys/deploy"), None);
+        // Every call so far, which must all have used the key
+        let calls = || {
+            let mut text = String::new();
+            let _ = File::open(&log).map(|mut f| f.read_to_string(&mut text));
+            assert!(text.lines().all(|c| c.starts_with("-i /keys/deploy -o IdentitiesOnly=yes")));
+            text.lines().count()
+        };
+
+        assert!(cli().clone_repo(url, &clone.with_file_name("ssh-clone"), &opts).is_err());
+        let after_clone = calls();
+        git(&clone, &["remote", "set-url", "origin", url]);
+        assert!(cli().fetch(&clone, &opts).is_err());
+        let after_fetch = calls();
+        let head = git(&clone, &["rev-parse", "HEAD"]);
+        git(&clone, &["config", "-f", ".gitmodules", "submodule.sub.path", "sub"]);
+        git(&clone, &["config", "-f", ".gitmodules", "submodule.sub.url", url]);
+        git(&clone, &["update-index", "--add", "--cacheinfo", "160000", &head, "sub"]);
+        assert!(cli().update_submodules(&clone, &opts).is_err());
+
+        assert!(0 < after_clone && after_clone < after_fetch && after_fetch < calls());
+    }
+}
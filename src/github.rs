@@ -0,0 +1,259 @@
+use std::io::Read;
+use std::str::FromStr;
+use std::time::Duration;
+use hyper::Client;
+use hyper::header::{Authorization, ContentType, UserAgent};
+use hyper::mime::{Mime, TopLevel, SubLevel};
+use serde_json;
+use serde_json::Value as JsonValue;
+
+use conf::Project;
+use error::{Reason, Error, Result};
+use payload::{self, json_object, json_str};
+
+pub const DEFAULT_API_URL: &'static str = "https://api.github.com";
+
+// Read and write timeout of API requests in seconds, so that a slow API
+// doesn't hold up deploys
+const API_TIMEOUT: u64 = 30;
+
+/// How deploys are reported back to GitHub
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReportMode {
+    Status,
+    Deployment,
+}
+
+impl FromStr for ReportMode {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> ::std::result::Result<ReportMode, &'static str> {
+        match s {
+            "status" => Ok(ReportMode::Status),
+            "deployment" => Ok(ReportMode::Deployment),
+            _ => Err("GitHub report mode must be either status or deployment"),
+        }
+    }
+}
+
+/// Reports the progress of a single deploy as a commit status,
+/// or as a deployment and its statuses.
+pub struct Reporter<'a> {
+    api: &'a str,
+    project: &'a Project,
+    token: &'a str,
+    sha: Option<String>,
+    deployment: Option<u64>,
+}
+
+impl<'a> Reporter<'a> {
+    /// Projects without an API token aren't reported
+    pub fn new(api: &'a str, project: &'a Project) -> Option<Reporter<'a>> {
+        project.github_token.as_ref().map(|token| {
+            Reporter {
+                api: api.trim_right_matches('/'),
+                project: project,
+                token: token,
+                sha: None,
+                deployment: None,
+            }
+        })
+    }
+
+    pub fn pending(&mut self, sha: &str) -> Result<()> {
+        self.sha = Some(sha.to_owned());
+        self.report(sha, "pending")
+    }
+
+    /// Finishes the report for the commit reported as pending,
+    /// or for the given commit if nothing was reported yet.
+    pub fn finish(&mut self, sha: &str, success: bool) -> Result<()> {
+        let sha = self.sha.clone().unwrap_or(sha.to_owned());
+        self.report(&sha, if success { "success" } else { "failure" })
+    }
+
+    fn report(&mut self, sha: &str, state: &str) -> Result<()> {
+        match self.project.github_report {
+            ReportMode::Status => {
+                let url = format!("{}/repos/{}/statuses/{}", self.api, self.project.repo, sha);
+                self.post(&url, &status_body(self.project, state)).map(|_| ())
+            }
+            ReportMode::Deployment => {
+                let id = match self.deployment {
+                    Some(id) => id,
+                    None => {
+                        let id = try!(self.create_deployment(sha));
+                        self.deployment = Some(id);
+                        id
+                    }
+                };
+                let url = format!("{}/repos/{}/deployments/{}/statuses",
+                                  self.api,
+                                  self.project.repo,
+                                  id);
+                self.post(&url, &deployment_status_body(state)).map(|_| ())
+            }
+        }
+    }
+
+    fn create_deployment(&self, sha: &str) -> Result<u64> {
+        let url = format!("{}/repos/{}/deployments", self.api, self.project.repo);
+        let response = try!(self.post(&url, &deployment_body(self.project, sha)));
+        response.lookup("id")
+                .and_then(|id| id.as_u64())
+                .ok_or(Error::app(Reason::MissingFields, "No deployment ID in response"))
+    }
+
+    fn post(&self, url: &str, body: &JsonValue) -> Result<JsonValue> {
+        let body = try!(serde_json::to_string(body));
+        let mut client = Client::new();
+        client.set_read_timeout(Some(Duration::from_secs(API_TIMEOUT)));
+        client.set_write_timeout(Some(Duration::from_secs(API_TIMEOUT)));
+        let mut response = try!(client.post(url)
+                                .header(Authorization(format!("token {}", self.token)))
+                                .header(UserAgent("koukku".to_owned()))
+                                .header(ContentType(Mime(TopLevel::Application,
+                                                         SubLevel::Json,
+                                                         vec![])))
+                                .body(&body[..])
+                                .send());
+        let mut text = String::new();
+        let _ = try!(response.read_to_string(&mut text));
+        if response.status.is_success() {
+            payload::str_to_json(&text)
+        } else {
+            let msg = format!("GitHub API responded with status {} to {}", response.status, url);
+            Err(Error::app(Reason::CommandFailed, msg))
+        }
+    }
+}
+
+fn status_body(project: &Project, state: &str) -> JsonValue {
+    json_object(vec![("state", json_str(state)),
+                     ("context", JsonValue::String(format!("koukku/{}", project.id))),
+                     ("description", json_str(description(state)))])
+}
+
+// Deploys are triggered by pushes, so GitHub shouldn't merge
+// or wait for status checks on our behalf.
+fn deployment_body(project: &Project, sha: &str) -> JsonValue {
+    json_object(vec![("ref", json_str(sha)),
+                     ("environment", json_str(&project.github_environment)),
+                     ("auto_merge", JsonValue::Bool(false)),
+                     ("required_contexts", JsonValue::Array(Vec::new())),
+                     ("description", JsonValue::String(format!("koukku/{}", project.id)))])
+}
+
+fn deployment_status_body(state: &str) -> JsonValue {
+    json_object(vec![("state", json_str(state)), ("description", json_str(description(state)))])
+}
+
+fn description(state: &str) -> &'static str {
+    match state {
+        "pending" => "Deploy in progress",
+        "success" => "Deployed successfully",
+        _ => "Deploy failed",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ReportMode, Reporter, status_body, deployment_body};
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+    use ini::Ini;
+    use conf::Conf;
+    use payload;
+
+    const CONF: &'static str = "location = /tmp/koukku\n\
+                                github_api = http://localhost:9999/\n\
+                                [koukku]\n\
+                                repo = Lepovirta/koukku\n\
+                                key = foobar\n\
+                                command = dostuff.sh\n\
+                                github_token = secret\n\
+                                github_report = deployment\n\
+                                [other]\n\
+                                repo = Lepovirta/other\n\
+                                key = foobar\n\
+                                command = dostuff.sh\n";
+
+    fn conf() -> Conf {
+        Conf::from_ini(&Ini::load_from_str(CONF).unwrap()).unwrap()
+    }
+
+    // Accepts a single request, answers it with an empty JSON object,
+    // and returns the request line, headers and body
+    fn mock_api() -> (String, thread::JoinHandle<(String, String)>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let handle = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut head = String::new();
+            let mut length = 0;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line.to_lowercase().starts_with("content-length:") {
+                    length = line[15..].trim().parse().unwrap();
+                }
+                if line.trim().is_empty() {
+                    break;
+                }
+                head.push_str(&line);
+            }
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).unwrap();
+            reader.get_mut()
+                  .write_all(b"HTTP/1.1 201 Created\r\nContent-Length: 2\r\n\
+                               Connection: close\r\n\r\n{}")
+                  .unwrap();
+            (head, String::from_utf8(body).unwrap())
+        });
+        (url, handle)
+    }
+
+    #[test]
+    fn posts_commit_status() {
+        let (url, handle) = mock_api();
+        let mut conf = conf();
+        conf.projects.get_mut("koukku").unwrap().github_report = ReportMode::Status;
+        let project = conf.get_project("koukku").unwrap();
+
+        Reporter::new(&url, project).unwrap().finish("abc123", true).unwrap();
+
+        let (head, body) = handle.join().unwrap();
+        assert!(head.starts_with("POST /repos/Lepovirta/koukku/statuses/abc123 HTTP/1.1\r\n"));
+        assert!(head.contains("Authorization: token secret\r\n"));
+        let json = payload::str_to_json(&body).unwrap();
+        assert_eq!(json.lookup("state").and_then(|v| v.as_string()), Some("success"));
+        assert_eq!(json.lookup("context").and_then(|v| v.as_string()),
+                   Some("koukku/koukku"));
+    }
+
+    #[test]
+    fn reports_only_projects_with_token() {
+        let conf = conf();
+
+        assert!(Reporter::new(&conf.github_api, conf.get_project("koukku").unwrap()).is_some());
+        assert!(Reporter::new(&conf.github_api, conf.get_project("other").unwrap()).is_none());
+    }
+
+    #[test]
+    fn builds_request_bodies() {
+        let conf = conf();
+        let project = conf.get_project("koukku").unwrap();
+
+        assert_eq!(project.github_report, ReportMode::Deployment);
+        let status = status_body(project, "pending");
+        assert_eq!(status.lookup("context").and_then(|v| v.as_string()),
+                   Some("koukku/koukku"));
+        let deployment = deployment_body(project, "abc123");
+        assert_eq!(deployment.lookup("ref").and_then(|v| v.as_string()),
+                   Some("abc123"));
+        assert_eq!(deployment.lookup("environment").and_then(|v| v.as_string()),
+                   Some("production"));
+    }
+}
@@ -14,6 +14,15 @@ pub enum GithubEvent {
     Push,
 }
 
+impl GithubEvent {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            GithubEvent::Ping => "ping",
+            GithubEvent::Push => "push",
+        }
+    }
+}
+
 impl Header for GithubEvent {
     fn header_name() -> &'static str {
         "X-Github-Event"
@@ -34,10 +43,7 @@ impl Header for GithubEvent {
 
 impl HeaderFormat for GithubEvent {
     fn fmt_header(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match *self {
-            GithubEvent::Ping => f.write_str("ping"),
-            GithubEvent::Push => f.write_str("push"),
-        }
+        f.write_str(self.name())
     }
 }
 
@@ -111,6 +117,13 @@ pub fn get_signature(headers: &Headers) -> Result<HubSignature, Error> {
     get_header::<HubSignature>(headers).map(|h| h.to_owned())
 }
 
+pub fn get_delivery(headers: &Headers) -> Option<String> {
+    headers.get_raw("X-GitHub-Delivery")
+           .and_then(|raw| raw.first())
+           .and_then(|line| str::from_utf8(line).ok())
+           .map(|s| s.to_owned())
+}
+
 fn get_header<H: Header + HeaderFormat>(headers: &Headers) -> Result<&H, Error> {
     headers.get::<H>().ok_or(missing_header::<H>())
 }
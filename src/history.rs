@@ -0,0 +1,242 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+use serde_json;
+use serde_json::Value as JsonValue;
+
+use error::{Reason, Error, Result};
+use job::Trigger;
+use payload::{self, json_object, json_str};
+
+// Older entries are dropped when the history grows past this
+const MAX_ENTRIES: usize = 500;
+
+// Shorter prefixes, down to git's default abbreviation, are likely to be ambiguous
+const MIN_COMMIT_PREFIX: usize = 7;
+
+pub const SUCCESS: &'static str = "success";
+
+/// A single deploy run of a project
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entry {
+    pub job: String,
+    pub commit: Option<String>,
+    pub time: u64,
+    pub outcome: String,
+    pub trigger: String,
+}
+
+impl Entry {
+    pub fn new(job: &str, commit: Option<String>, outcome: &str, trigger: &str) -> Entry {
+        let time = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        Entry {
+            job: job.to_owned(),
+            commit: commit,
+            time: time,
+            outcome: outcome.to_owned(),
+            trigger: trigger.to_owned(),
+        }
+    }
+
+    fn is_success(&self) -> bool {
+        self.outcome == SUCCESS
+    }
+
+    fn to_json(&self) -> JsonValue {
+        let mut fields = vec![("job", json_str(&self.job)),
+                              ("time", JsonValue::U64(self.time)),
+                              ("outcome", json_str(&self.outcome)),
+                              ("trigger", json_str(&self.trigger))];
+        if let Some(ref commit) = self.commit {
+            fields.push(("commit", json_str(commit)));
+        }
+        json_object(fields)
+    }
+
+    fn from_json(json: &JsonValue) -> Option<Entry> {
+        let field = |name: &str| payload::get_string(json, name).map(|s| s.to_owned());
+        let time = json.lookup("time").and_then(|v| v.as_u64());
+        match (field("job"), time, field("outcome"), field("trigger")) {
+            (Some(job), Some(time), Some(outcome), Some(trigger)) => {
+                Some(Entry {
+                    job: job,
+                    commit: field("commit"),
+                    time: time,
+                    outcome: outcome,
+                    trigger: trigger,
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Deploy history of projects, stored next to the project repositories
+/// in `<location>/<id>.history` with one JSON object per line.
+#[derive(Clone)]
+pub struct History {
+    location: PathBuf,
+}
+
+impl History {
+    pub fn new(location: &str) -> History {
+        History { location: PathBuf::from(location) }
+    }
+
+    fn path(&self, id: &str) -> PathBuf {
+        self.location.join(format!("{}.history", id))
+    }
+
+    pub fn entries(&self, id: &str) -> Result<Vec<Entry>> {
+        let path = self.path(id);
+        if !path.exists() {
+            return Ok(Vec::new());
+        }
+        let file = try!(File::open(&path));
+        let mut entries = Vec::new();
+        for line in BufReader::new(file).lines() {
+            let line = try!(line);
+            match payload::str_to_json(&line).ok().as_ref().and_then(Entry::from_json) {
+                Some(entry) => entries.push(entry),
+                None => warn!("Skipping invalid history entry in {}", path.display()),
+            }
+        }
+        Ok(entries)
+    }
+
+    pub fn record(&self, id: &str, entry: Entry) -> Result<()> {
+        let mut entries = try!(self.entries(id));
+        if entries.len() >= MAX_ENTRIES {
+            // Rewrite the file without the oldest entries
+            let excess = entries.len() + 1 - MAX_ENTRIES;
+            entries.drain(..excess);
+            entries.push(entry);
+            self.write_all(id, &entries)
+        } else {
+            let mut file = try!(OpenOptions::new().create(true).append(true).open(self.path(id)));
+            let line = try!(serde_json::to_string(&entry.to_json()));
+            writeln!(file, "{}", line).map_err(Error::from)
+        }
+    }
+
+    fn write_all(&self, id: &str, entries: &[Entry]) -> Result<()> {
+        let path = self.path(id);
+        let tmp_path = path.with_extension("history.tmp");
+        {
+            let mut file = try!(File::create(&tmp_path));
+            for entry in entries {
+                let line = try!(serde_json::to_string(&entry.to_json()));
+                try!(writeln!(file, "{}", line));
+            }
+        }
+        fs::rename(&tmp_path, &path).map_err(Error::from)
+    }
+
+    /// Finds the commit to roll back to: either the successfully deployed
+    /// commit matching `to`, or the last successful commit before the current one.
+    /// Rollbacks themselves are skipped, so rolling back twice doesn't return
+    /// to the commit that was rolled back from.
+    pub fn rollback_target(&self, id: &str, to: Option<&str>) -> Result<String> {
+        let entries = try!(self.entries(id));
+        rollback_target(&entries, to)
+    }
+}
+
+fn rollback_target(entries: &[Entry], to: Option<&str>) -> Result<String> {
+    if to.map_or(false, |sha| sha.len() < MIN_COMMIT_PREFIX) {
+        let msg = format!("The commit to roll back to needs at least {} characters",
+                          MIN_COMMIT_PREFIX);
+        return Err(Error::app(Reason::InvalidRevision, msg));
+    }
+    let mut deployed = entries.iter()
+                              .rev()
+                              .filter(|e| e.is_success() && e.trigger != Trigger::Rollback.name())
+                              .filter_map(|e| e.commit.as_ref());
+    let target = match to {
+        Some(sha) => {
+            let mut matches = deployed.filter(|commit| commit.starts_with(sha));
+            let first = matches.next();
+            if matches.any(|commit| Some(commit) != first) {
+                let msg = format!("Commit {} matches several deployed commits", sha);
+                return Err(Error::app(Reason::InvalidRevision, msg));
+            }
+            first
+        }
+        None => {
+            let current = deployed.next();
+            deployed.find(|commit| Some(*commit) != current)
+        }
+    };
+    target.map(|t| t.to_owned()).ok_or(match to {
+        Some(sha) => {
+            let msg = format!("No successful deploy found for commit {}", sha);
+            Error::app(Reason::MissingRevision, msg)
+        }
+        None => Error::app(Reason::MissingRevision, "No previous successful deploy found"),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Entry, rollback_target};
+
+    fn entry(commit: &str, outcome: &str) -> Entry {
+        Entry::new("job", Some(commit.to_owned()), outcome, "webhook")
+    }
+
+    fn rollback(commit: &str) -> Entry {
+        Entry::new("job", Some(commit.to_owned()), "success", "rollback")
+    }
+
+    #[test]
+    fn rolls_back_to_previous_success() {
+        let entries = vec![entry("aaa", "success"),
+                           entry("bbb", "failure"),
+                           entry("ccc", "success"),
+                           entry("ccc", "success")];
+
+        assert_eq!(rollback_target(&entries, None).unwrap(), "aaa");
+    }
+
+    #[test]
+    fn rolling_back_twice_keeps_previous_success() {
+        let entries = vec![entry("aaa", "success"), entry("bbb", "success"), rollback("aaa")];
+
+        assert_eq!(rollback_target(&entries, None).unwrap(), "aaa");
+    }
+
+    #[test]
+    fn rolls_back_to_given_commit() {
+        let entries = vec![entry("aaaaaaa111", "success"), entry("bbbbbbb222", "success")];
+
+        assert_eq!(rollback_target(&entries, Some("aaaaaaa")).unwrap(), "aaaaaaa111");
+        assert!(rollback_target(&entries, Some("aaa")).is_err());
+    }
+
+    #[test]
+    fn refuses_ambiguous_commits() {
+        let entries = vec![entry("aaaaaaa111", "success"),
+                           entry("aaaaaaa222", "success"),
+                           entry("aaaaaaa222", "success")];
+
+        assert!(rollback_target(&entries, Some("aaaaaaa")).is_err());
+        assert_eq!(rollback_target(&entries, Some("aaaaaaa2")).unwrap(), "aaaaaaa222");
+    }
+
+    #[test]
+    fn refuses_failed_commits() {
+        let entries = vec![entry("aaaaaaa", "success"), entry("bbbbbbb", "failure")];
+
+        assert!(rollback_target(&entries, Some("bbbbbbb")).is_err());
+        assert!(rollback_target(&entries, Some("")).is_err());
+        assert!(rollback_target(&entries, None).is_err());
+    }
+
+    #[test]
+    fn entry_roundtrip() {
+        let e = entry("aaa", "success");
+
+        assert_eq!(Entry::from_json(&e.to_json()), Some(e));
+    }
+}
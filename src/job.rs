@@ -0,0 +1,64 @@
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+static NEXT_JOB: AtomicUsize = ATOMIC_USIZE_INIT;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Trigger {
+    Webhook,
+    Manual,
+    Rollback,
+    Cli,
+}
+
+impl Trigger {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Trigger::Webhook => "webhook",
+            Trigger::Manual => "manual",
+            Trigger::Rollback => "rollback",
+            Trigger::Cli => "cli",
+        }
+    }
+}
+
+/// A request for the executor to update a project
+#[derive(Debug, Clone, PartialEq)]
+pub struct Job {
+    pub id: String,
+    pub project: String,
+    pub trigger: Trigger,
+    pub delivery: Option<String>,
+    pub commit: Option<String>,
+    /// Branch to deploy instead of the project branch
+    pub branch: Option<String>,
+    /// Revision to check out after updating the branch
+    pub revision: Option<String>,
+}
+
+impl Job {
+    pub fn new(project: &str, trigger: Trigger) -> Job {
+        Job {
+            id: next_id(),
+            project: project.to_owned(),
+            trigger: trigger,
+            delivery: None,
+            commit: None,
+            branch: None,
+            revision: None,
+        }
+    }
+
+    /// Jobs started by hand run the update command even if there are no changes
+    pub fn is_forced(&self) -> bool {
+        self.trigger != Trigger::Webhook
+    }
+}
+
+// Job IDs are unique within a process, and prefixed with the creation time
+// to keep them apart between restarts.
+fn next_id() -> String {
+    let seq = NEXT_JOB.fetch_add(1, Ordering::SeqCst);
+    let secs = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    format!("{}-{}", secs, seq)
+}
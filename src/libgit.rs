@@ -0,0 +1,674 @@
+use std::cell::RefCell;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex, Once};
+use std::time::Duration;
+use git2::{self, Branch, BranchType, Commit, FetchOptions, Oid, Progress, RemoteCallbacks,
+           Repository, ResetType, StatusOptions};
+use git2::build::{CheckoutBuilder, RepoBuilder};
+use git2::transport::{self, Service, SmartSubtransport, SmartSubtransportStream, Transport};
+use hyper::{Client, Url};
+use hyper::client::Response;
+use hyper::header::{Authorization, Basic, ContentType, Headers, UserAgent};
+use hyper::mime::{Mime, TopLevel, SubLevel};
+
+use conf::{ExecOptions, GitOptions, TokenSource};
+use error::{Reason, Result, Error};
+use git::GitBackend;
+
+// Fetching without refspecs uses the ones configured for the remote
+const NO_REFSPECS: &'static [&'static str] = &[];
+
+// Read and write timeout of the HTTP requests made while fetching
+const HTTP_TIMEOUT: u64 = 300;
+
+// Tokens are only sent to the host that projects are cloned from,
+// like with the credential helper of the git binary
+const TOKEN_HOST: &'static str = "github.com";
+
+const UPLOAD_PACK_LS: &'static str = "/info/refs?service=git-upload-pack";
+const UPLOAD_PACK: &'static str = "/git-upload-pack";
+
+static REGISTER_TRANSPORT: Once = Once::new();
+
+// The HTTPS token of the project being fetched on this thread. The HTTP
+// transport is created by libgit2, so the token can't be passed to it directly.
+thread_local!(static TOKEN: RefCell<Option<TokenSource>> = RefCell::new(None));
+
+/// libgit2 doesn't support partial or sparse clones, submodule updates or LFS.
+/// Shallow clones and SSH would need libgit2 to be built with OpenSSL, which
+/// conflicts with the OpenSSL version of hyper. Git runs in-process, so it
+/// can't switch to the user of the project either.
+pub fn check_options(git: &GitOptions,
+                     exec: &ExecOptions)
+                     -> ::std::result::Result<(), &'static str> {
+    if git.depth.is_some() || git.filter.is_some() || !git.sparse_paths.is_empty() {
+        Err("depth, filter and sparse_paths are not supported by the libgit2 backend")
+    } else if git.submodules || git.lfs {
+        Err("submodules and lfs are not supported by the libgit2 backend")
+    } else if git.ssh_key.is_some() {
+        Err("ssh_key is not supported by the libgit2 backend")
+    } else if exec.user.is_some() || exec.group.is_some() {
+        Err("user and group are not supported by the libgit2 backend")
+    } else {
+        Ok(())
+    }
+}
+
+/// Updates repositories in-process with libgit2, without a git binary.
+/// HTTP and HTTPS remotes are fetched with hyper.
+pub struct Libgit2;
+
+impl Libgit2 {
+    pub fn new() -> Libgit2 {
+        REGISTER_TRANSPORT.call_once(|| {
+            for scheme in &["http", "https"] {
+                // Registering is unsafe when transports are created at the
+                // same time, which can't happen before the first backend exists
+                let result = unsafe {
+                    transport::register(scheme, |remote| {
+                        Transport::smart(remote, true, try!(HttpTransport::new()))
+                    })
+                };
+                if let Err(err) = result {
+                    error!("Failed to register the {} transport of libgit2: {}", scheme, err);
+                }
+            }
+        });
+        Libgit2
+    }
+}
+
+impl GitBackend for Libgit2 {
+    fn clone_repo(&self, url: &str, path: &Path, opts: &GitOptions) -> Result<()> {
+        with_token(opts, || {
+            let _ = try!(RepoBuilder::new().fetch_options(fetch_options()).clone(url, path));
+            Ok(())
+        })
+    }
+
+    fn sparse_checkout(&self, _: &Path, _: &GitOptions) -> Result<()> {
+        Ok(())
+    }
+
+    fn checkout(&self, path: &Path, rev: &str) -> Result<()> {
+        let repo = try!(Repository::open(path));
+        checkout(&repo, rev, false)
+    }
+
+    fn fetch(&self, path: &Path, opts: &GitOptions) -> Result<()> {
+        let repo = try!(Repository::open(path));
+        let remotes = try!(repo.remotes());
+        with_token(opts, || {
+            for name in remotes.iter().filter_map(|n| n) {
+                let mut remote = try!(repo.find_remote(name));
+                try!(remote.fetch(NO_REFSPECS, Some(&mut fetch_options()), None));
+            }
+            Ok(())
+        })
+    }
+
+    fn diverged(&self, path: &Path) -> Result<bool> {
+        let repo = try!(Repository::open(path));
+        let local = try!(head_oid(&repo));
+        let remote = try!(upstream_oid(&repo));
+        if local == remote {
+            return Ok(false);
+        }
+        repo.graph_descendant_of(remote, local).map(|ahead| !ahead).map_err(Error::from)
+    }
+
+    // Only fast-forwards, like `git pull --ff-only`
+    fn pull(&self, path: &Path, _: &str, opts: &GitOptions) -> Result<()> {
+        let repo = try!(Repository::open(path));
+        let mut origin = try!(repo.find_remote("origin"));
+        try!(with_token(opts, || {
+            origin.fetch(NO_REFSPECS, Some(&mut fetch_options()), None).map_err(Error::from)
+        }));
+        let local = try!(head_oid(&repo));
+        let remote = try!(upstream_oid(&repo));
+        if local == remote {
+            return Ok(());
+        }
+        if !try!(repo.graph_descendant_of(remote, local)) {
+            let msg = "Local branch has diverged from the remote and can't be fast-forwarded";
+            return Err(Error::app(Reason::CommandFailed, msg));
+        }
+        let target = try!(repo.find_object(remote, None));
+        try!(repo.checkout_tree(&target, Some(CheckoutBuilder::new().safe())));
+        let mut head = try!(repo.head());
+        let _ = try!(head.set_target(remote, "pull: fast-forward"));
+        Ok(())
+    }
+
+    // Shallow clones aren't supported, so the history is always complete
+    fn deepen(&self, _: &Path, _: &GitOptions) -> Result<()> {
+        Ok(())
+    }
+
+    fn update_submodules(&self, _: &Path, _: &GitOptions) -> Result<()> {
+        Ok(())
+    }
+
+    fn lfs_pull(&self, _: &Path, _: &GitOptions) -> Result<()> {
+        Ok(())
+    }
+
+    fn head(&self, path: &Path) -> Result<String> {
+        let repo = try!(Repository::open(path));
+        head_oid(&repo).map(|oid| oid.to_string())
+    }
+
+    fn remote_url(&self, path: &Path) -> Result<String> {
+        let repo = try!(Repository::open(path));
+        let remote = try!(repo.find_remote("origin"));
+        remote.url()
+              .map(|url| url.to_owned())
+              .ok_or(Error::app(Reason::InvalidRepository, "Remote URL is not valid UTF-8"))
+    }
+
+    fn has_local_changes(&self, path: &Path) -> Result<bool> {
+        let repo = try!(Repository::open(path));
+        let mut opts = StatusOptions::new();
+        opts.include_untracked(false).include_ignored(false);
+        let statuses = try!(repo.statuses(Some(&mut opts)));
+        Ok(!statuses.is_empty())
+    }
+
+    fn reset(&self, path: &Path, url: &str, branch: &str, opts: &GitOptions) -> Result<()> {
+        let repo = try!(Repository::open(path));
+        try!(repo.remote_set_url("origin", url));
+        let mut origin = try!(repo.find_remote("origin"));
+        try!(with_token(opts, || {
+            origin.fetch(NO_REFSPECS, Some(&mut fetch_options()), None).map_err(Error::from)
+        }));
+        // A corrupted index is rebuilt by the hard reset. It's only removed
+        // after fetching, so a failed fetch leaves the repository as it was.
+        let index = path.join(".git").join("index");
+        if index.exists() {
+            info!("Removing index {}", index.display());
+            try!(fs::remove_file(&index));
+        }
+
+        let remote_branch = format!("origin/{}", branch);
+        let remote = try!(repo.find_branch(&remote_branch, BranchType::Remote));
+        let commit = try!(branch_commit(&repo, &remote));
+        // The branch can't be moved while HEAD points to it
+        try!(repo.set_head_detached(commit.id()));
+        let mut local = try!(repo.branch(branch, &commit, true));
+        try!(local.set_upstream(Some(&remote_branch)));
+        try!(repo.set_head(&format!("refs/heads/{}", branch)));
+        try!(repo.reset(commit.as_object(), ResetType::Hard, None));
+        remove_untracked(&repo, path)
+    }
+
+    fn create_release(&self, repo: &Path, release: &Path, sha: &str, _: &GitOptions) -> Result<()> {
+        let url = try!(repo.to_str()
+                           .ok_or(Error::app(Reason::InvalidPath, "Invalid project path")));
+        let cloned = try!(RepoBuilder::new().clone(url, release));
+        checkout(&cloned, sha, true)
+    }
+}
+
+// Branches that only exist in the remote are created locally, tracking the
+// remote branch. Other revisions are checked out with a detached HEAD.
+fn checkout(repo: &Repository, rev: &str, force: bool) -> Result<()> {
+    let mut builder = CheckoutBuilder::new();
+    if force {
+        builder.force();
+    } else {
+        builder.safe();
+    }
+    let remote_branch = format!("origin/{}", rev);
+    if repo.find_branch(rev, BranchType::Local).is_err() {
+        if let Ok(remote) = repo.find_branch(&remote_branch, BranchType::Remote) {
+            let commit = try!(branch_commit(repo, &remote));
+            let mut local = try!(repo.branch(rev, &commit, false));
+            try!(local.set_upstream(Some(&remote_branch)));
+        }
+    }
+    match repo.find_branch(rev, BranchType::Local) {
+        Ok(branch) => {
+            let refname = try!(branch.get()
+                                     .name()
+                                     .map(|n| n.to_owned())
+                                     .ok_or(Error::app(Reason::InvalidRevision,
+                                                       "Invalid branch name")));
+            let commit = try!(branch_commit(repo, &branch));
+            try!(repo.checkout_tree(commit.as_object(), Some(&mut builder)));
+            repo.set_head(&refname).map_err(Error::from)
+        }
+        Err(_) => {
+            let target = try!(repo.revparse_single(&format!("{}^{{commit}}", rev)));
+            try!(repo.checkout_tree(&target, Some(&mut builder)));
+            repo.set_head_detached(target.id()).map_err(Error::from)
+        }
+    }
+}
+
+fn branch_commit<'r>(repo: &'r Repository, branch: &Branch) -> Result<Commit<'r>> {
+    let oid = try!(branch.get()
+                         .target()
+                         .ok_or(Error::app(Reason::InvalidRevision, "Branch has no target")));
+    repo.find_commit(oid).map_err(Error::from)
+}
+
+fn head_oid(repo: &Repository) -> Result<Oid> {
+    let head = try!(repo.head());
+    head.target().ok_or(Error::app(Reason::InvalidRevision, "HEAD is not a direct reference"))
+}
+
+fn upstream_oid(repo: &Repository) -> Result<Oid> {
+    let head = try!(repo.head());
+    let name = try!(head.shorthand()
+                        .ok_or(Error::app(Reason::InvalidRevision, "HEAD is not on a branch")));
+    let branch = try!(repo.find_branch(name, BranchType::Local));
+    let upstream = try!(branch.upstream());
+    upstream.get()
+            .target()
+            .ok_or(Error::app(Reason::InvalidRevision, "Upstream is not a direct reference"))
+}
+
+// Same as `git clean -d`: ignored files are kept
+fn remove_untracked(repo: &Repository, path: &Path) -> Result<()> {
+    let mut opts = StatusOptions::new();
+    opts.include_untracked(true).include_ignored(false).recurse_untracked_dirs(false);
+    let statuses = try!(repo.statuses(Some(&mut opts)));
+    for entry in statuses.iter().filter(|e| e.status().contains(git2::Status::WT_NEW)) {
+        if let Some(name) = entry.path() {
+            let file = path.join(name);
+            info!("Removing untracked {}", file.display());
+            if file.is_dir() {
+                try!(fs::remove_dir_all(&file));
+            } else {
+                try!(fs::remove_file(&file));
+            }
+        }
+    }
+    Ok(())
+}
+
+// Makes the token of the project available to the HTTP transport while `f` runs
+fn with_token<T, F: FnOnce() -> Result<T>>(opts: &GitOptions, f: F) -> Result<T> {
+    TOKEN.with(|token| *token.borrow_mut() = opts.token.clone());
+    let result = f();
+    TOKEN.with(|token| *token.borrow_mut() = None);
+    result
+}
+
+// Transfer progress is logged at every tenth of the received objects
+fn fetch_options<'a>() -> FetchOptions<'a> {
+    let mut last = 0;
+    let mut callbacks = RemoteCallbacks::new();
+    callbacks.transfer_progress(move |progress: Progress| {
+        let total = progress.total_objects();
+        let received = progress.received_objects();
+        if total > 0 && received * 10 / total > last {
+            last = received * 10 / total;
+            info!("Received {}/{} objects ({} bytes)",
+                  received,
+                  total,
+                  progress.received_bytes());
+        }
+        true
+    });
+    let mut opts = FetchOptions::new();
+    opts.remote_callbacks(callbacks);
+    opts
+}
+
+/// The smart HTTP protocol over hyper. Only fetching is supported.
+struct HttpTransport {
+    // URL of the repository, changed when the server redirects
+    base_url: Arc<Mutex<Option<String>>>,
+    token: Option<String>,
+}
+
+impl HttpTransport {
+    // The token is read when libgit2 connects to the remote, on the thread fetching it
+    fn new() -> ::std::result::Result<HttpTransport, git2::Error> {
+        let token = try!(TOKEN.with(|token| {
+            match *token.borrow() {
+                Some(ref source) => {
+                    source.read()
+                          .map(Some)
+                          .map_err(|err| git2::Error::from_str(&err.to_string()))
+                }
+                None => Ok(None),
+            }
+        }));
+        Ok(HttpTransport {
+            base_url: Arc::new(Mutex::new(None)),
+            token: token,
+        })
+    }
+}
+
+impl SmartSubtransport for HttpTransport {
+    fn action(&self,
+              url: &str,
+              action: Service)
+              -> ::std::result::Result<Box<SmartSubtransportStream>, git2::Error> {
+        let mut base_url = try!(self.base_url
+                                    .lock()
+                                    .map_err(|err| git2::Error::from_str(&err.to_string())));
+        let base = base_url.get_or_insert(url.trim_right_matches('/').to_owned()).clone();
+        let path = match action {
+            Service::UploadPackLs => UPLOAD_PACK_LS,
+            Service::UploadPack => UPLOAD_PACK,
+            Service::ReceivePackLs | Service::ReceivePack => {
+                return Err(git2::Error::from_str("Pushing is not supported"))
+            }
+        };
+        let token = if send_token(&base) {
+            self.token.clone()
+        } else {
+            None
+        };
+        Ok(Box::new(HttpStream {
+            url: format!("{}{}", base, path),
+            base_url: self.base_url.clone(),
+            post: action == Service::UploadPack,
+            token: token,
+            body: Vec::new(),
+            response: None,
+        }))
+    }
+
+    fn close(&self) -> ::std::result::Result<(), git2::Error> {
+        Ok(())
+    }
+}
+
+fn send_token(url: &str) -> bool {
+    Url::parse(url)
+        .map(|url| url.scheme == "https" && url.domain() == Some(TOKEN_HOST))
+        .unwrap_or(false)
+}
+
+// Requests are sent on the first read. Until then, writes are collected
+// into the body of the request.
+struct HttpStream {
+    url: String,
+    base_url: Arc<Mutex<Option<String>>>,
+    post: bool,
+    token: Option<String>,
+    body: Vec<u8>,
+    response: Option<Response>,
+}
+
+impl HttpStream {
+    fn send(&self) -> io::Result<Response> {
+        let mut client = Client::new();
+        client.set_read_timeout(Some(Duration::from_secs(HTTP_TIMEOUT)));
+        client.set_write_timeout(Some(Duration::from_secs(HTTP_TIMEOUT)));
+        let mut headers = Headers::new();
+        headers.set(UserAgent(format!("git/2.0 (koukku {})", env!("CARGO_PKG_VERSION"))));
+        if let Some(ref token) = self.token {
+            headers.set(Authorization(Basic {
+                username: "x-access-token".to_owned(),
+                password: Some(token.to_owned()),
+            }));
+        }
+        let request = if self.post {
+            let request_type = SubLevel::Ext("x-git-upload-pack-request".to_owned());
+            headers.set(ContentType(Mime(TopLevel::Application, request_type, vec![])));
+            client.post(&self.url[..]).body(&self.body[..])
+        } else {
+            client.get(&self.url[..])
+        };
+        let response = try!(request.headers(headers)
+                                   .send()
+                                   .map_err(|err| io::Error::new(io::ErrorKind::Other, err)));
+        if !response.status.is_success() {
+            let msg = format!("Request to {} failed with status {}", self.url, response.status);
+            return Err(io::Error::new(io::ErrorKind::Other, msg));
+        }
+        // Later requests go to where the repository was redirected to
+        let final_url = response.url.serialize();
+        if !self.post && final_url != self.url && final_url.ends_with(UPLOAD_PACK_LS) {
+            let base = final_url[..final_url.len() - UPLOAD_PACK_LS.len()].to_owned();
+            if let Ok(mut base_url) = self.base_url.lock() {
+                *base_url = Some(base);
+            }
+        }
+        Ok(response)
+    }
+}
+
+impl Read for HttpStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.response.is_none() {
+            self.response = Some(try!(self.send()));
+        }
+        match self.response {
+            Some(ref mut response) => response.read(buf),
+            None => Ok(0),
+        }
+    }
+}
+
+impl Write for HttpStream {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.body.extend_from_slice(data);
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::path::{Path, PathBuf};
+    use std::process::{Command, Stdio};
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use super::{Libgit2, check_options, send_token};
+    use conf::{ExecOptions, GitOptions, TokenSource};
+    use git::GitBackend;
+
+    fn git(dir: &Path, args: &[&str]) -> String {
+        let out = Command::new("git")
+                      .current_dir(dir)
+                      .args(args)
+                      .env("GIT_AUTHOR_NAME", "koukku")
+                      .env("GIT_AUTHOR_EMAIL", "koukku@example.com")
+                      .env("GIT_COMMITTER_NAME", "koukku")
+                      .env("GIT_COMMITTER_EMAIL", "koukku@example.com")
+                      .output()
+                      .unwrap();
+        assert!(out.status.success(), "git {:?} failed", args);
+        String::from_utf8(out.stdout).unwrap().trim().to_owned()
+    }
+
+    fn commit(dir: &Path, content: &str) -> String {
+        File::create(dir.join("index.html")).unwrap().write_all(content.as_bytes()).unwrap();
+        git(dir, &["add", "index.html"]);
+        git(dir, &["commit", "--quiet", "-m", content]);
+        git(dir, &["rev-parse", "HEAD"])
+    }
+
+    fn push(work: &Path) {
+        git(work, &["push", "--quiet", "origin", "HEAD:refs/heads/master"]);
+    }
+
+    // A bare origin with one commit on master, and a work repository for pushing to it
+    fn repositories(name: &str) -> (PathBuf, PathBuf) {
+        let dir = env::temp_dir().join(format!("koukku-libgit-test-{}", name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let (origin, work) = (dir.join("origin.git"), dir.join("work"));
+        git(&dir, &["init", "--quiet", "--bare", origin.to_str().unwrap()]);
+        git(&origin, &["symbolic-ref", "HEAD", "refs/heads/master"]);
+        git(&dir, &["init", "--quiet", work.to_str().unwrap()]);
+        git(&work, &["remote", "add", "origin", origin.to_str().unwrap()]);
+        commit(&work, "first");
+        push(&work);
+        (origin, work)
+    }
+
+    // Serves the repositories next to `origin` with `git http-backend`, one
+    // request per connection. Returns the base URL and the requests made,
+    // with their authorization header if any.
+    fn serve(origin: &Path) -> (String, Arc<Mutex<Vec<String>>>) {
+        let root = origin.parent().unwrap().to_owned();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let seen = requests.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut request = String::new();
+                reader.read_line(&mut request).unwrap();
+                let (mut length, mut content_type, mut auth) = (0, String::new(), String::new());
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    let mut header = line.trim_right().splitn(2, ": ");
+                    match (header.next().unwrap().to_lowercase().as_str(), header.next()) {
+                        ("content-length", Some(value)) => length = value.parse().unwrap(),
+                        ("content-type", Some(value)) => content_type = value.to_owned(),
+                        ("authorization", Some(value)) => auth = value.to_owned(),
+                        ("", _) => break,
+                        _ => (),
+                    }
+                }
+                let mut body = vec![0; length];
+                reader.read_exact(&mut body).unwrap();
+                let mut parts = request.split(' ');
+                let method = parts.next().unwrap().to_owned();
+                let target = parts.next().unwrap().to_owned();
+                let line = format!("{} {} {}", method, target, auth);
+                seen.lock().unwrap().push(line.trim().to_owned());
+                let mut target = target.splitn(2, '?');
+                let mut child = Command::new("git")
+                                    .arg("http-backend")
+                                    .env("GIT_PROJECT_ROOT", &root)
+                                    .env("GIT_HTTP_EXPORT_ALL", "1")
+                                    .env("REQUEST_METHOD", method)
+                                    .env("PATH_INFO", target.next().unwrap())
+                                    .env("QUERY_STRING", target.next().unwrap_or(""))
+                                    .env("CONTENT_TYPE", content_type)
+                                    .env("CONTENT_LENGTH", length.to_string())
+                                    .stdin(Stdio::piped())
+                                    .stdout(Stdio::piped())
+                                    .spawn()
+                                    .unwrap();
+                child.stdin.take().unwrap().write_all(&body).unwrap();
+                let out = child.wait_with_output().unwrap().stdout;
+                let end = out.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+                let headers = String::from_utf8_lossy(&out[..end]).into_owned();
+                let status = headers.lines()
+                                    .find(|h| h.starts_with("Status: "))
+                                    .map(|h| h[8..].to_owned())
+                                    .unwrap_or("200 OK".to_owned());
+                let response = format!("HTTP/1.1 {}\r\n{}\r\nContent-Length: {}\r\n\
+                                        Connection: close\r\n\r\n",
+                                       status,
+                                       headers,
+                                       out.len() - end - 4);
+                stream.write_all(response.as_bytes()).unwrap();
+                stream.write_all(&out[end + 4..]).unwrap();
+            }
+        });
+        (url, requests)
+    }
+
+    #[test]
+    fn clones_and_pulls_over_http() {
+        let (origin, work) = repositories("http");
+        let (url, requests) = serve(&origin);
+        let clone = work.with_file_name("clone");
+        env::set_var("KOUKKU_LIBGIT_TEST_TOKEN", "s3cr3t");
+        let opts = GitOptions {
+            token: Some(TokenSource::Env("KOUKKU_LIBGIT_TEST_TOKEN".to_owned())),
+            ..GitOptions::default()
+        };
+        let backend = Libgit2::new();
+
+        backend.clone_repo(&format!("{}/origin.git", url), &clone, &opts).unwrap();
+        let remote = commit(&work, "second");
+        push(&work);
+        backend.fetch(&clone, &opts).unwrap();
+        backend.pull(&clone, "master", &opts).unwrap();
+
+        assert_eq!(backend.head(&clone).unwrap(), remote);
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests[0], "GET /origin.git/info/refs?service=git-upload-pack");
+        assert_eq!(requests[1], "POST /origin.git/git-upload-pack");
+        // The token is only sent to GitHub
+        assert!(requests.iter().all(|r| !r.contains("Basic")));
+    }
+
+    #[test]
+    fn resets_to_remote_branch() {
+        let (origin, work) = repositories("reset");
+        let clone = work.with_file_name("clone");
+        let opts = GitOptions::default();
+        let backend = Libgit2::new();
+        backend.clone_repo(origin.to_str().unwrap(), &clone, &opts).unwrap();
+        commit(&clone, "local");
+        File::create(clone.join("index.html")).unwrap().write_all(b"modified").unwrap();
+        File::create(clone.join("untracked.txt")).unwrap();
+        File::create(clone.join(".git").join("index")).unwrap().write_all(b"corrupt").unwrap();
+        let remote = commit(&work, "second");
+        push(&work);
+
+        backend.reset(&clone, origin.to_str().unwrap(), "master", &opts).unwrap();
+
+        assert_eq!(backend.head(&clone).unwrap(), remote);
+        assert!(!backend.has_local_changes(&clone).unwrap());
+        assert!(!clone.join("untracked.txt").exists());
+    }
+
+    #[test]
+    fn checks_out_remote_only_branch_and_refuses_diverged_pull() {
+        let (origin, work) = repositories("checkout");
+        let clone = work.with_file_name("clone");
+        let opts = GitOptions::default();
+        let backend = Libgit2::new();
+        backend.clone_repo(origin.to_str().unwrap(), &clone, &opts).unwrap();
+        git(&work, &["checkout", "--quiet", "-b", "release"]);
+        let release = commit(&work, "release");
+        git(&work, &["push", "--quiet", "origin", "release"]);
+
+        backend.fetch(&clone, &opts).unwrap();
+        backend.checkout(&clone, "release").unwrap();
+        assert_eq!(backend.head(&clone).unwrap(), release);
+        assert!(!backend.diverged(&clone).unwrap());
+
+        let local = commit(&clone, "local");
+        commit(&work, "second");
+        git(&work, &["push", "--quiet", "origin", "release"]);
+        assert!(backend.pull(&clone, "release", &opts).is_err());
+        assert!(backend.diverged(&clone).unwrap());
+        assert_eq!(backend.head(&clone).unwrap(), local);
+    }
+
+    #[test]
+    fn sends_token_to_github_over_https_only() {
+        assert!(send_token("https://github.com/Lepovirta/koukku.git"));
+        assert!(!send_token("http://github.com/Lepovirta/koukku.git"));
+        assert!(!send_token("https://example.com/Lepovirta/koukku.git"));
+    }
+
+    #[test]
+    fn rejects_unsupported_options() {
+        let exec = ExecOptions::default();
+        let shallow = GitOptions { depth: Some(1), ..GitOptions::default() };
+        let as_user = ExecOptions { user: Some("nobody".to_owned()), ..ExecOptions::default() };
+
+        assert!(check_options(&GitOptions::default(), &exec).is_ok());
+        assert!(check_options(&shallow, &exec).is_err());
+        assert!(check_options(&GitOptions::default(), &as_user).is_err());
+    }
+}
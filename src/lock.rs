@@ -0,0 +1,161 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::str::FromStr;
+use std::thread;
+use std::time::{Duration, Instant};
+use libc;
+
+use error::{Reason, Result, Error};
+
+// How often a locked project is checked while waiting
+const POLL_INTERVAL_MS: u64 = 100;
+
+/// What to do when another process is already updating the project
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LockPolicy {
+    Wait,
+    Skip,
+}
+
+impl FromStr for LockPolicy {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> ::std::result::Result<LockPolicy, &'static str> {
+        match s {
+            "wait" => Ok(LockPolicy::Wait),
+            "skip" => Ok(LockPolicy::Skip),
+            _ => Err("on_locked must be either wait or skip"),
+        }
+    }
+}
+
+/// Exclusive lock on a project, shared by every koukku instance using the
+/// same location. The lock is released when this is dropped.
+pub struct ProjectLock {
+    _file: File,
+}
+
+/// Locks `<location>/<id>.lock` with `flock`, and writes the commit being
+/// deployed to it. Returns None if the project is locked by a deploy of the
+/// same commit and the policy is to skip. Deploys of other commits wait for
+/// the lock, so that a newer commit is never dropped. Waiting for longer
+/// than `timeout` is an error. The location is created if it doesn't exist.
+pub fn acquire(location: &str,
+               id: &str,
+               policy: LockPolicy,
+               commit: Option<&str>,
+               timeout: Duration)
+               -> Result<Option<ProjectLock>> {
+    try!(fs::create_dir_all(location));
+    let path = Path::new(location).join(format!("{}.lock", id));
+    let mut file = try!(OpenOptions::new().read(true).write(true).create(true).open(&path));
+    let start = Instant::now();
+    let mut waiting = false;
+    while !try!(flock(&file, libc::LOCK_EX | libc::LOCK_NB)) {
+        if policy == LockPolicy::Skip && commit.is_some() {
+            let holder = try!(locked_commit(&mut file));
+            if holder.as_ref().map(|c| &c[..]) == commit {
+                return Ok(None);
+            }
+        }
+        if start.elapsed() >= timeout {
+            let msg = format!("Timed out waiting for lock {}", path.display());
+            return Err(Error::app(Reason::CommandFailed, msg));
+        }
+        if !waiting {
+            info!("Waiting for lock {}", path.display());
+            waiting = true;
+        }
+        thread::sleep(Duration::from_millis(POLL_INTERVAL_MS));
+    }
+    try!(file.set_len(0));
+    try!(file.seek(SeekFrom::Start(0)));
+    try!(file.write_all(commit.unwrap_or("").as_bytes()));
+    Ok(Some(ProjectLock { _file: file }))
+}
+
+// The commit written by the holder of the lock, if any
+fn locked_commit(file: &mut File) -> Result<Option<String>> {
+    let mut commit = String::new();
+    try!(file.seek(SeekFrom::Start(0)));
+    let _ = try!(file.read_to_string(&mut commit));
+    Ok(if commit.is_empty() {
+        None
+    } else {
+        Some(commit)
+    })
+}
+
+// Returns false if a non-blocking lock is held by someone else
+fn flock(file: &File, operation: libc::c_int) -> io::Result<bool> {
+    loop {
+        if unsafe { libc::flock(file.as_raw_fd(), operation) } == 0 {
+            return Ok(true);
+        }
+        let err = io::Error::last_os_error();
+        match err.raw_os_error() {
+            Some(libc::EINTR) => continue,
+            Some(libc::EWOULDBLOCK) => return Ok(false),
+            _ => return Err(err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{LockPolicy, acquire};
+    use std::env;
+    use std::fs;
+    use std::time::{Duration, Instant};
+
+    fn lock_dir(name: &str) -> String {
+        let dir = env::temp_dir().join(name);
+        fs::create_dir_all(&dir).unwrap();
+        dir.to_str().unwrap().to_owned()
+    }
+
+    #[test]
+    fn skips_locked_project_for_same_commit() {
+        let location = lock_dir("koukku-lock-test");
+        let timeout = Duration::from_secs(1);
+        let skip = |id, commit| acquire(&location, id, LockPolicy::Skip, commit, timeout);
+
+        let lock = skip("site", Some("aaa")).unwrap();
+        assert!(lock.is_some());
+        assert!(skip("site", Some("aaa")).unwrap().is_none());
+        assert!(skip("other", Some("aaa")).unwrap().is_some());
+
+        drop(lock);
+        assert!(skip("site", Some("aaa")).unwrap().is_some());
+    }
+
+    #[test]
+    fn waits_for_other_commits_until_timeout() {
+        let location = lock_dir("koukku-lock-timeout-test");
+        let timeout = Duration::from_millis(300);
+        let _lock = acquire(&location, "site", LockPolicy::Skip, Some("aaa"), timeout).unwrap();
+        let start = Instant::now();
+
+        assert!(acquire(&location, "site", LockPolicy::Skip, Some("bbb"), timeout).is_err());
+        assert!(acquire(&location, "site", LockPolicy::Wait, Some("aaa"), timeout).is_err());
+        assert!(start.elapsed() >= timeout * 2);
+    }
+
+    #[test]
+    fn creates_missing_location() {
+        let location = env::temp_dir().join("koukku-lock-location-test");
+        let _ = fs::remove_dir_all(&location);
+        let timeout = Duration::from_secs(1);
+
+        let lock = acquire(location.join("new").to_str().unwrap(),
+                           "site",
+                           LockPolicy::Wait,
+                           None,
+                           timeout);
+
+        assert!(lock.unwrap().is_some());
+        let _ = fs::remove_dir_all(&location);
+    }
+}
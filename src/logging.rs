@@ -0,0 +1,102 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::env;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use env_logger::LogBuilder;
+use log::{LogRecord, SetLoggerError};
+use serde_json::Value as JsonValue;
+
+use job::Job;
+use payload;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<LogFormat, &'static str> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err("Log format must be either text or json"),
+        }
+    }
+}
+
+/// Correlation fields attached to every log line written from the current thread
+#[derive(Debug, Clone, Default)]
+pub struct Context {
+    pub delivery: Option<String>,
+    pub project: Option<String>,
+    pub repo: Option<String>,
+    pub commit: Option<String>,
+    pub job: Option<String>,
+}
+
+impl Context {
+    pub fn for_job(job: &Job, repo: Option<&str>) -> Context {
+        Context {
+            delivery: job.delivery.clone(),
+            project: Some(job.project.clone()),
+            repo: repo.map(|r| r.to_owned()),
+            commit: job.commit.clone(),
+            job: Some(job.id.clone()),
+        }
+    }
+
+    fn fields(&self) -> Vec<(&'static str, &Option<String>)> {
+        vec![("delivery", &self.delivery),
+             ("project", &self.project),
+             ("repo", &self.repo),
+             ("commit", &self.commit),
+             ("job", &self.job)]
+    }
+}
+
+thread_local!(static CONTEXT: RefCell<Context> = RefCell::new(Context::default()));
+
+pub fn set_context(context: Context) {
+    CONTEXT.with(|c| *c.borrow_mut() = context);
+}
+
+pub fn update_context<F: FnOnce(&mut Context)>(f: F) {
+    CONTEXT.with(|c| f(&mut c.borrow_mut()));
+}
+
+pub fn clear_context() {
+    set_context(Context::default());
+}
+
+pub fn init(format: LogFormat) -> Result<(), SetLoggerError> {
+    let mut builder = LogBuilder::new();
+    if format == LogFormat::Json {
+        builder.format(format_json);
+    }
+    if let Ok(filters) = env::var("RUST_LOG") {
+        builder.parse(&filters);
+    }
+    builder.init()
+}
+
+fn format_json(record: &LogRecord) -> String {
+    let mut line = BTreeMap::new();
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or(Duration::from_secs(0));
+    let timestamp = now.as_secs() as f64 + now.subsec_nanos() as f64 / 1e9;
+    line.insert("time".to_owned(), JsonValue::F64(timestamp));
+    line.insert("level".to_owned(), JsonValue::String(record.level().to_string()));
+    line.insert("target".to_owned(), JsonValue::String(record.target().to_owned()));
+    line.insert("message".to_owned(), JsonValue::String(record.args().to_string()));
+    CONTEXT.with(|c| {
+        for (key, value) in c.borrow().fields() {
+            if let Some(ref v) = *value {
+                line.insert(key.to_owned(), JsonValue::String(v.to_owned()));
+            }
+        }
+    });
+    payload::json_to_string(&JsonValue::Object(line))
+}
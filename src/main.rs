@@ -8,16 +8,38 @@ extern crate log;
 extern crate env_logger;
 extern crate clap;
 extern crate serde_json;
+extern crate libc;
+#[cfg(feature = "libgit2")]
+extern crate git2;
 
+mod client;
 mod error;
 mod header;
 mod server;
 mod conf;
 mod payload;
 mod exec;
+mod git;
+mod github;
+mod job;
+mod lock;
+#[cfg(feature = "libgit2")]
+mod libgit;
+mod history;
+mod logging;
+mod metrics;
+mod notify;
+mod privileges;
+mod ratelimit;
+mod replay;
+#[cfg(target_os = "linux")]
+mod sandbox;
+mod status;
 
-use clap::{Arg, App};
+use clap::{Arg, App, ArgMatches, SubCommand};
+use std::process;
 use std::thread;
+use std::sync::Arc;
 use std::sync::mpsc::channel;
 use std::io::{self, Write};
 
@@ -27,7 +49,7 @@ macro_rules! try_log(
             Ok(v) => v,
             Err(e) => {
                 let _ = writeln!(&mut io::stderr(), "Error: {}", e);
-                return;
+                process::exit(1);
             }
         }
     }}
@@ -45,26 +67,114 @@ fn main() {
                                .help("Configuration file location")
                                .takes_value(true)
                                .required(true))
+                      .subcommand(SubCommand::with_name("trigger")
+                                      .about("Triggers an update in a running koukku server")
+                                      .arg(Arg::with_name("project")
+                                               .help("ID of the project to update")
+                                               .required(true)
+                                               .index(1))
+                                      .arg(Arg::with_name("branch")
+                                               .long("branch")
+                                               .value_name("BRANCH")
+                                               .help("Branch to deploy instead of the \
+                                                      project branch")
+                                               .takes_value(true))
+                                      .arg(Arg::with_name("commit")
+                                               .long("commit")
+                                               .value_name("SHA")
+                                               .help("Commit to deploy")
+                                               .takes_value(true))
+                                      .arg(Arg::with_name("url")
+                                               .long("url")
+                                               .value_name("URL")
+                                               .help("koukku server URL. Default: based on \
+                                                      the server configuration")
+                                               .takes_value(true)))
+                      .subcommand(SubCommand::with_name("run")
+                                      .about("Updates a project once without starting the \
+                                              server")
+                                      .arg(Arg::with_name("project")
+                                               .help("ID of the project to update")
+                                               .required(true)
+                                               .index(1)))
+                      .subcommand(SubCommand::with_name("rollback")
+                                      .about("Rolls a project back to a previously deployed \
+                                              commit in a running koukku server")
+                                      .arg(Arg::with_name("project")
+                                               .help("ID of the project to roll back")
+                                               .required(true)
+                                               .index(1))
+                                      .arg(Arg::with_name("to")
+                                               .long("to")
+                                               .value_name("SHA")
+                                               .help("Commit to roll back to. Default: the \
+                                                      previous successful deploy")
+                                               .takes_value(true))
+                                      .arg(Arg::with_name("url")
+                                               .long("url")
+                                               .value_name("URL")
+                                               .help("koukku server URL. Default: based on \
+                                                      the server configuration")
+                                               .takes_value(true)))
                       .get_matches();
 
     let config = try_log!(matches.value_of("config")
                                  .ok_or("No config location specified"));
-    start(&config);
+    match matches.subcommand() {
+        ("trigger", Some(sub)) => trigger(&config, sub),
+        ("run", Some(sub)) => run(&config, sub),
+        ("rollback", Some(sub)) => rollback(&config, sub),
+        _ => start(&config),
+    }
+}
+
+fn trigger(config: &str, matches: &ArgMatches) {
+    let conf = try_log!(conf::Conf::from_file(config));
+    let project = try_log!(matches.value_of("project").ok_or("No project specified"));
+    let response = try_log!(client::trigger(&conf,
+                                            matches.value_of("url"),
+                                            project,
+                                            matches.value_of("branch"),
+                                            matches.value_of("commit")));
+    println!("{}", response);
+}
+
+fn rollback(config: &str, matches: &ArgMatches) {
+    let conf = try_log!(conf::Conf::from_file(config));
+    let project = try_log!(matches.value_of("project").ok_or("No project specified"));
+    let response = try_log!(client::rollback(&conf,
+                                             matches.value_of("url"),
+                                             project,
+                                             matches.value_of("to")));
+    println!("{}", response);
+}
+
+fn run(config: &str, matches: &ArgMatches) {
+    let conf = try_log!(conf::Conf::from_file(config));
+    let _ = try_log!(logging::init(conf.log_format));
+    let project = try_log!(matches.value_of("project").ok_or("No project specified"));
+
+    let executor = exec::Executor::new(conf, Arc::new(status::Status::new()))
+                       .with_output(exec::OutputMode::Inherit);
+    let job = job::Job::new(project, job::Trigger::Cli);
+    // Exit with the status of the update command when it fails
+    match executor.execute(&job) {
+        Err(error::Error::CommandExit(_, code, _)) => process::exit(code),
+        result => try_log!(result),
+    }
 }
 
 fn start(config: &str) {
-    let _ = try_log!(env_logger::init());
     let conf = try_log!(conf::Conf::from_file(config));
-    let server = conf.server.clone();
-    let threads = conf.threads;
-    let projects = conf.projects.clone();
+    let _ = try_log!(logging::init(conf.log_format));
 
     let (tx, rx) = channel();
-    let executor = exec::Executor::new(conf, rx);
+    let status = Arc::new(status::Status::new());
+    let executor = exec::Executor::new(conf.clone(), status.clone());
 
     info!("Starting koukku server");
 
-    thread::spawn(move || executor.start());
+    thread::spawn(move || executor.start(rx));
 
-    let _ = try_log!(server::start(&server, threads, projects, tx));
+    let _ = try_log!(server::start(&conf, tx, status));
 }
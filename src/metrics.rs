@@ -0,0 +1,279 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+const DURATION_BUCKETS: &'static [f64] = &[0.1, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0,
+                                           300.0, 600.0];
+
+type Labels = Vec<(&'static str, String)>;
+
+struct Histogram {
+    buckets: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn new() -> Histogram {
+        Histogram {
+            buckets: vec![0; DURATION_BUCKETS.len()],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+
+    fn observe(&mut self, value: f64) {
+        for (i, bound) in DURATION_BUCKETS.iter().enumerate() {
+            if value <= *bound {
+                self.buckets[i] += 1;
+            }
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+}
+
+#[derive(Default)]
+struct Registry {
+    deliveries: BTreeMap<Labels, u64>,
+    ignored: BTreeMap<Labels, u64>,
+    failures: BTreeMap<Labels, u64>,
+    signature_failures: u64,
+    rate_limited: BTreeMap<Labels, u64>,
+    git_steps: BTreeMap<Labels, Histogram>,
+    commands: BTreeMap<Labels, Histogram>,
+    command_exits: BTreeMap<Labels, u64>,
+    jobs: BTreeMap<Labels, u64>,
+    last_success: BTreeMap<String, SystemTime>,
+}
+
+/// Counters and histograms exported in the Prometheus text format.
+/// Recording a metric never fails: a poisoned lock only drops the measurement.
+pub struct Metrics {
+    registry: Mutex<Registry>,
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics { registry: Mutex::new(Registry::default()) }
+    }
+
+    pub fn delivery(&self, event: &str) {
+        self.update(|r| increment(&mut r.deliveries, vec![("event", event.to_owned())]));
+    }
+
+    pub fn ignored(&self, project: &str) {
+        self.update(|r| increment(&mut r.ignored, vec![("project", project.to_owned())]));
+    }
+
+    pub fn failure(&self, reason: &str) {
+        self.update(|r| increment(&mut r.failures, vec![("reason", reason.to_owned())]));
+    }
+
+    pub fn signature_failure(&self) {
+        self.update(|r| r.signature_failures += 1);
+    }
+
+    pub fn rate_limited(&self, scope: &str) {
+        self.update(|r| increment(&mut r.rate_limited, vec![("scope", scope.to_owned())]));
+    }
+
+    pub fn git_step(&self, step: &str, duration: Duration) {
+        self.update(|r| observe(&mut r.git_steps, vec![("step", step.to_owned())], duration));
+    }
+
+    pub fn command(&self, project: &str, duration: Duration, exit_code: Option<i32>) {
+        let code = exit_code.map(|c| c.to_string()).unwrap_or("signal".to_owned());
+        self.update(|r| {
+            observe(&mut r.commands, vec![("project", project.to_owned())], duration);
+            increment(&mut r.command_exits,
+                      vec![("project", project.to_owned()), ("exit_code", code)]);
+        });
+    }
+
+    pub fn job(&self, project: &str, trigger: &str, outcome: &str) {
+        let labels = vec![("project", project.to_owned()),
+                          ("trigger", trigger.to_owned()),
+                          ("outcome", outcome.to_owned())];
+        self.update(|r| increment(&mut r.jobs, labels));
+    }
+
+    pub fn deploy_succeeded(&self, project: &str) {
+        self.update(|r| {
+            r.last_success.insert(project.to_owned(), SystemTime::now());
+        });
+    }
+
+    fn update<F: FnOnce(&mut Registry)>(&self, f: F) {
+        if let Ok(mut registry) = self.registry.lock() {
+            f(&mut registry);
+        }
+    }
+
+    /// Renders all metrics. The queue depth is owned by the caller.
+    pub fn render(&self, queue_depth: usize) -> String {
+        let mut out = String::new();
+        let registry = match self.registry.lock() {
+            Ok(registry) => registry,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+
+        write_counters(&mut out,
+                       "koukku_deliveries_total",
+                       "Webhook deliveries received by event type",
+                       &registry.deliveries);
+        write_counters(&mut out,
+                       "koukku_ignored_deliveries_total",
+                       "Deliveries accepted but ignored, such as pushes to untracked branches",
+                       &registry.ignored);
+        write_counters(&mut out,
+                       "koukku_failures_total",
+                       "Failed requests by reason",
+                       &registry.failures);
+        write_header(&mut out,
+                     "koukku_signature_failures_total",
+                     "Deliveries with an invalid signature",
+                     "counter");
+        write_sample(&mut out,
+                     "koukku_signature_failures_total",
+                     &Labels::new(),
+                     registry.signature_failures as f64);
+        write_counters(&mut out,
+                       "koukku_rate_limited_total",
+                       "Requests rejected by a rate limit",
+                       &registry.rate_limited);
+        write_header(&mut out,
+                     "koukku_queue_depth",
+                     "Updates waiting for the executor",
+                     "gauge");
+        write_sample(&mut out, "koukku_queue_depth", &Labels::new(), queue_depth as f64);
+        write_histograms(&mut out,
+                         "koukku_git_step_duration_seconds",
+                         "Duration of git steps",
+                         &registry.git_steps);
+        write_histograms(&mut out,
+                         "koukku_command_duration_seconds",
+                         "Duration of update commands",
+                         &registry.commands);
+        write_counters(&mut out,
+                       "koukku_command_exits_total",
+                       "Update command runs by exit code",
+                       &registry.command_exits);
+        write_counters(&mut out,
+                       "koukku_jobs_total",
+                       "Jobs run by the executor by trigger and outcome",
+                       &registry.jobs);
+
+        write_header(&mut out,
+                     "koukku_seconds_since_last_success",
+                     "Time since the last successful deploy",
+                     "gauge");
+        let now = SystemTime::now();
+        for (project, time) in registry.last_success.iter() {
+            let elapsed = now.duration_since(*time).unwrap_or(Duration::from_secs(0));
+            write_sample(&mut out,
+                         "koukku_seconds_since_last_success",
+                         &vec![("project", project.to_owned())],
+                         seconds(elapsed));
+        }
+        out
+    }
+}
+
+fn increment(family: &mut BTreeMap<Labels, u64>, labels: Labels) {
+    *family.entry(labels).or_insert(0) += 1;
+}
+
+fn observe(family: &mut BTreeMap<Labels, Histogram>, labels: Labels, duration: Duration) {
+    family.entry(labels).or_insert_with(Histogram::new).observe(seconds(duration));
+}
+
+fn seconds(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + duration.subsec_nanos() as f64 / 1e9
+}
+
+fn write_header(out: &mut String, name: &str, help: &str, kind: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn write_sample(out: &mut String, name: &str, labels: &Labels, value: f64) {
+    let _ = writeln!(out, "{}{} {}", name, format_labels(labels), value);
+}
+
+fn write_counters(out: &mut String, name: &str, help: &str, family: &BTreeMap<Labels, u64>) {
+    write_header(out, name, help, "counter");
+    for (labels, value) in family.iter() {
+        write_sample(out, name, labels, *value as f64);
+    }
+}
+
+fn write_histograms(out: &mut String,
+                    name: &str,
+                    help: &str,
+                    family: &BTreeMap<Labels, Histogram>) {
+    write_header(out, name, help, "histogram");
+    let bucket_name = format!("{}_bucket", name);
+    for (labels, histogram) in family.iter() {
+        for (bound, count) in DURATION_BUCKETS.iter().zip(histogram.buckets.iter()) {
+            let mut bucket_labels = labels.clone();
+            bucket_labels.push(("le", bound.to_string()));
+            write_sample(out, &bucket_name, &bucket_labels, *count as f64);
+        }
+        let mut inf_labels = labels.clone();
+        inf_labels.push(("le", "+Inf".to_owned()));
+        write_sample(out, &bucket_name, &inf_labels, histogram.count as f64);
+        write_sample(out, &format!("{}_sum", name), labels, histogram.sum);
+        write_sample(out, &format!("{}_count", name), labels, histogram.count as f64);
+    }
+}
+
+fn format_labels(labels: &Labels) -> String {
+    if labels.is_empty() {
+        return String::new();
+    }
+    let pairs = labels.iter()
+                      .map(|&(k, ref v)| format!("{}=\"{}\"", k, escape(v)))
+                      .collect::<Vec<_>>();
+    format!("{{{}}}", pairs.join(","))
+}
+
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Metrics;
+    use std::time::Duration;
+
+    #[test]
+    fn renders_counters() {
+        let metrics = Metrics::new();
+        metrics.delivery("push");
+        metrics.delivery("push");
+        metrics.failure("invalid_signature");
+
+        let out = metrics.render(3);
+
+        assert!(out.contains("koukku_deliveries_total{event=\"push\"} 2\n"));
+        assert!(out.contains("koukku_failures_total{reason=\"invalid_signature\"} 1\n"));
+        assert!(out.contains("koukku_queue_depth 3\n"));
+    }
+
+    #[test]
+    fn renders_histograms() {
+        let metrics = Metrics::new();
+        metrics.command("site", Duration::from_millis(700), Some(1));
+
+        let out = metrics.render(0);
+
+        assert!(out.contains("koukku_command_duration_seconds_bucket{project=\"site\",le=\"0.5\"} \
+                              0\n"));
+        assert!(out.contains("koukku_command_duration_seconds_bucket{project=\"site\",le=\"1\"} \
+                              1\n"));
+        assert!(out.contains("koukku_command_duration_seconds_count{project=\"site\"} 1\n"));
+        assert!(out.contains("koukku_command_exits_total{project=\"site\",exit_code=\"1\"} 1\n"));
+    }
+}
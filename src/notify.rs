@@ -0,0 +1,238 @@
+use std::io::Write;
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+use hyper::Client;
+use hyper::header::ContentType;
+use hyper::mime::{Mime, TopLevel, SubLevel};
+use serde_json;
+use serde_json::Value as JsonValue;
+
+use conf::{Notify, Project};
+use error::{Reason, Error, Result};
+use history;
+use payload::{json_object, json_str};
+
+// Number of output lines included in notifications
+const TAIL_LINES: usize = 20;
+
+// How often a running notification command is checked for having exited
+const POLL_INTERVAL_MS: u64 = 100;
+
+/// A finished deploy to notify about
+pub struct Deploy<'a> {
+    pub project: &'a Project,
+    pub job: &'a str,
+    pub branch: &'a str,
+    pub commit: Option<&'a str>,
+    pub outcome: &'a str,
+    pub duration: Duration,
+    pub output: &'a str,
+}
+
+impl<'a> Deploy<'a> {
+    fn is_success(&self) -> bool {
+        self.outcome == history::SUCCESS
+    }
+
+    fn seconds(&self) -> f64 {
+        self.duration.as_secs() as f64 + self.duration.subsec_nanos() as f64 / 1e9
+    }
+
+    fn summary(&self) -> String {
+        let result = if self.is_success() {
+            "succeeded"
+        } else {
+            "failed"
+        };
+        format!("Deploy of {} ({}@{} {}) {} in {:.1}s",
+                self.project.id,
+                self.project.repo,
+                self.branch,
+                self.commit.unwrap_or("unknown commit"),
+                result,
+                self.seconds())
+    }
+
+    fn text(&self) -> String {
+        format!("{}\n\nRepository: {}\nBranch: {}\nCommit: {}\nDuration: {:.1}s\n\nOutput:\n{}\n",
+                self.summary(),
+                self.project.repo,
+                self.branch,
+                self.commit.unwrap_or("unknown"),
+                self.seconds(),
+                tail(self.output, TAIL_LINES))
+    }
+
+    // The `text` field makes the payload usable with Slack and Mattermost
+    // compatible incoming webhooks as is.
+    fn to_json(&self) -> JsonValue {
+        let mut fields = vec![("text", JsonValue::String(self.summary())),
+                              ("project", json_str(&self.project.id)),
+                              ("repo", json_str(&self.project.repo)),
+                              ("branch", json_str(self.branch)),
+                              ("job", json_str(self.job)),
+                              ("outcome", json_str(self.outcome)),
+                              ("duration", JsonValue::F64(self.seconds())),
+                              ("output", JsonValue::String(tail(self.output, TAIL_LINES)))];
+        if let Some(commit) = self.commit {
+            fields.push(("commit", json_str(commit)));
+        }
+        json_object(fields)
+    }
+}
+
+/// Sends the deploy notification to every configured target.
+/// Failing targets are only logged.
+pub fn send(notify: &Notify, deploy: &Deploy) {
+    if deploy.is_success() && !notify.on_success {
+        return;
+    }
+    if let Some(ref url) = notify.webhook {
+        log_failure("webhook", post_webhook(url, notify.timeout, deploy));
+    }
+    if !notify.email.is_empty() {
+        log_failure("email", send_mail(notify, deploy));
+    }
+    if let Some(ref command) = notify.command {
+        log_failure("command", run_notifier(command, notify.timeout, deploy));
+    }
+}
+
+fn log_failure(target: &str, result: Result<()>) {
+    if let Err(err) = result {
+        warn!("Failed to send {} notification: {}", target, err);
+    }
+}
+
+fn post_webhook(url: &str, timeout: Duration, deploy: &Deploy) -> Result<()> {
+    let body = try!(serde_json::to_string(&deploy.to_json()));
+    let mut client = Client::new();
+    client.set_read_timeout(Some(timeout));
+    client.set_write_timeout(Some(timeout));
+    let response = try!(client.post(url)
+                        .header(ContentType(Mime(TopLevel::Application,
+                                                 SubLevel::Json,
+                                                 vec![])))
+                        .body(&body[..])
+                        .send());
+    if response.status.is_success() {
+        Ok(())
+    } else {
+        let msg = format!("Webhook responded with status {}", response.status);
+        Err(Error::app(Reason::CommandFailed, msg))
+    }
+}
+
+fn send_mail(notify: &Notify, deploy: &Deploy) -> Result<()> {
+    let mut child = try!(Command::new(&notify.sendmail)
+                             .arg("-t")
+                             .arg("-oi")
+                             .stdin(Stdio::piped())
+                             .stdout(Stdio::null())
+                             .spawn());
+    if let Some(mut stdin) = child.stdin.take() {
+        try!(write!(stdin,
+                    "To: {}\nSubject: [koukku] {}\n\n{}",
+                    notify.email.join(", "),
+                    deploy.summary(),
+                    deploy.text()));
+    }
+    wait_for(&notify.sendmail, child, notify.timeout)
+}
+
+fn run_notifier(command: &str, timeout: Duration, deploy: &Deploy) -> Result<()> {
+    let child = try!(Command::new(command)
+                         .env("KOUKKU_PROJECT", &deploy.project.id)
+                         .env("KOUKKU_REPO", &deploy.project.repo)
+                         .env("KOUKKU_BRANCH", deploy.branch)
+                         .env("KOUKKU_COMMIT", deploy.commit.unwrap_or(""))
+                         .env("KOUKKU_OUTCOME", deploy.outcome)
+                         .env("KOUKKU_DURATION", format!("{:.3}", deploy.seconds()))
+                         .env("KOUKKU_OUTPUT", tail(deploy.output, TAIL_LINES))
+                         .stdin(Stdio::null())
+                         .stdout(Stdio::null())
+                         .spawn());
+    wait_for(command, child, timeout)
+}
+
+// Kills the command if it doesn't exit in time, so that a hanging
+// notification doesn't hold up the next deploy
+fn wait_for(command: &str, mut child: Child, timeout: Duration) -> Result<()> {
+    let start = Instant::now();
+    loop {
+        match try!(child.try_wait()) {
+            Some(status) if status.success() => return Ok(()),
+            Some(status) => {
+                let msg = format!("{} exited with status {}", command, status);
+                return Err(Error::app(Reason::CommandFailed, msg));
+            }
+            None if start.elapsed() >= timeout => {
+                let _ = child.kill();
+                let _ = child.wait();
+                let msg = format!("{} timed out after {} seconds", command, timeout.as_secs());
+                return Err(Error::app(Reason::CommandFailed, msg));
+            }
+            None => thread::sleep(Duration::from_millis(POLL_INTERVAL_MS)),
+        }
+    }
+}
+
+fn tail(text: &str, lines: usize) -> String {
+    let all = text.lines().collect::<Vec<_>>();
+    let start = all.len().saturating_sub(lines);
+    all[start..].join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Deploy, tail, wait_for};
+    use std::process::Command;
+    use std::time::{Duration, Instant};
+    use ini::Ini;
+    use conf::Conf;
+
+    const CONF: &'static str = "location = /tmp/koukku\n\
+                                [koukku]\n\
+                                repo = Lepovirta/koukku\n\
+                                key = foobar\n\
+                                command = dostuff.sh\n";
+
+    #[test]
+    fn tail_keeps_last_lines() {
+        assert_eq!(tail("a\nb\nc\n", 2), "b\nc");
+        assert_eq!(tail("a", 2), "a");
+        assert_eq!(tail("", 2), "");
+    }
+
+    #[test]
+    fn kills_hanging_command() {
+        let child = Command::new("sleep").arg("10").spawn().unwrap();
+        let start = Instant::now();
+
+        assert!(wait_for("sleep", child, Duration::from_secs(1)).is_err());
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn json_describes_deploy() {
+        let conf = Conf::from_ini(&Ini::load_from_str(CONF).unwrap()).unwrap();
+        let deploy = Deploy {
+            project: conf.get_project("koukku").unwrap(),
+            job: "1-0",
+            branch: "master",
+            commit: Some("abc123"),
+            outcome: "failure",
+            duration: Duration::from_millis(1500),
+            output: "line 1\nline 2\n",
+        };
+
+        let json = deploy.to_json();
+
+        assert_eq!(json.lookup("text").and_then(|v| v.as_string()),
+                   Some("Deploy of koukku (Lepovirta/koukku@master abc123) failed in 1.5s"));
+        assert_eq!(json.lookup("output").and_then(|v| v.as_string()),
+                   Some("line 1\nline 2"));
+        assert_eq!(json.lookup("duration").and_then(|v| v.as_f64()), Some(1.5));
+    }
+}
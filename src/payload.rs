@@ -1,3 +1,4 @@
+use std::collections::BTreeMap;
 use std::str;
 use serde_json;
 use serde_json::Value as JsonValue;
@@ -17,6 +18,14 @@ pub fn get_branch(json: &JsonValue) -> Result<&str> {
         .ok_or(Error::app(Reason::MissingFields, "No branch found"))
 }
 
+pub fn get_commit(json: &JsonValue) -> Option<&str> {
+    get_string(json, "after")
+}
+
+pub fn get_string<'a>(json: &'a JsonValue, path: &str) -> Option<&'a str> {
+    json.lookup(path).and_then(|v| v.as_string())
+}
+
 pub fn bytes_to_json(bytes: &[u8]) -> Result<JsonValue> {
     let s = try!(str::from_utf8(bytes));
     str_to_json(s)
@@ -25,3 +34,19 @@ pub fn bytes_to_json(bytes: &[u8]) -> Result<JsonValue> {
 pub fn str_to_json(s: &str) -> Result<JsonValue> {
     serde_json::from_str(s).map_err(Error::from)
 }
+
+pub fn json_to_string(json: &JsonValue) -> String {
+    // Writing a JSON value to memory can't fail
+    serde_json::to_string(json).unwrap()
+}
+
+pub fn json_object(fields: Vec<(&str, JsonValue)>) -> JsonValue {
+    let map = fields.into_iter()
+                    .map(|(k, v)| (k.to_owned(), v))
+                    .collect::<BTreeMap<_, _>>();
+    JsonValue::Object(map)
+}
+
+pub fn json_str(s: &str) -> JsonValue {
+    JsonValue::String(s.to_owned())
+}
@@ -0,0 +1,282 @@
+use std::env;
+use std::ffi::{CStr, CString};
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::process::Command;
+use std::ptr;
+use libc;
+
+use conf::ExecOptions;
+use error::{Reason, Result, Error};
+
+// Buffer size for the reentrant passwd and group lookups
+const LOOKUP_BUFFER_SIZE: usize = 16384;
+
+#[derive(Clone)]
+struct Account {
+    uid: libc::uid_t,
+    gid: libc::gid_t,
+    name: String,
+    home: String,
+}
+
+/// User and group that the commands of a project are run as.
+/// Without a user or a group, commands are run as the koukku user.
+#[derive(Clone, Default)]
+pub struct RunAs {
+    account: Option<Account>,
+    gid: Option<libc::gid_t>,
+}
+
+impl RunAs {
+    /// The group defaults to the primary group of the user
+    pub fn lookup(opts: &ExecOptions) -> Result<RunAs> {
+        let account = match opts.user {
+            Some(ref user) => Some(try!(lookup_user(user))),
+            None => None,
+        };
+        let gid = match opts.group {
+            Some(ref group) => Some(try!(lookup_group(group))),
+            None => account.as_ref().map(|a| a.gid),
+        };
+        Ok(RunAs {
+            account: account,
+            gid: gid,
+        })
+    }
+
+    pub fn ids(&self) -> (Option<libc::uid_t>, Option<libc::gid_t>) {
+        (self.account.as_ref().map(|a| a.uid), self.gid)
+    }
+
+    /// Runs the command as the user and group, with the home directory of the user
+    pub fn apply(&self, command: &mut Command) {
+        self.set_env(command);
+        self.switch(command);
+    }
+
+    /// Creates a directory owned by the user and group, so that commands
+    /// run as them can write to it without write access to its parent.
+    /// Returns false without creating it if there's no user or group.
+    pub fn create_dir(&self, path: &Path) -> Result<bool> {
+        let (uid, gid) = self.ids();
+        if uid.is_none() && gid.is_none() {
+            return Ok(false);
+        }
+        try!(fs::create_dir_all(path));
+        let c_path = try!(CString::new(path.as_os_str().as_bytes())
+                              .map_err(|_| Error::app(Reason::InvalidPath, "Invalid path")));
+        // -1 leaves the owner or the group unchanged
+        let uid = uid.unwrap_or(!0);
+        let gid = gid.unwrap_or(!0);
+        if unsafe { libc::chown(c_path.as_ptr(), uid, gid) } != 0 {
+            return Err(Error::from(io::Error::last_os_error()));
+        }
+        Ok(true)
+    }
+
+    fn set_env(&self, command: &mut Command) {
+        if let Some(ref account) = self.account {
+            command.env("HOME", &account.home)
+                   .env("USER", &account.name)
+                   .env("LOGNAME", &account.name);
+        }
+    }
+
+    // The group is switched before the user, because the order can't be reversed
+    fn switch(&self, command: &mut Command) {
+        let (uid, gid) = self.ids();
+        if uid.is_none() && gid.is_none() {
+            return;
+        }
+        before_exec(command, move || {
+            if let Some(gid) = gid {
+                if unsafe { libc::setgroups(1, &gid) } != 0 ||
+                   unsafe { libc::setgid(gid) } != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            if let Some(uid) = uid {
+                if unsafe { libc::setuid(uid) } != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            Ok(())
+        });
+    }
+}
+
+/// Applies the user, group, umask, resource limits and environment
+/// of the project to a command before it's spawned.
+/// Sandboxed commands switch to the user and group in the sandbox instead.
+pub fn configure(command: &mut Command, opts: &ExecOptions) -> Result<()> {
+    let run_as = try!(RunAs::lookup(opts));
+    if let Some(ref allowed) = opts.env_allowlist {
+        command.env_clear();
+        for name in allowed {
+            if let Some(value) = env::var_os(name) {
+                command.env(name, value);
+            }
+        }
+    }
+    run_as.set_env(command);
+
+    let umask = opts.umask;
+    let limits = [(libc::RLIMIT_CPU, opts.cpu_limit),
+                  (libc::RLIMIT_AS, opts.memory_limit),
+                  (libc::RLIMIT_NOFILE, opts.open_files_limit)];
+    // Limits are set before dropping privileges
+    before_exec(command, move || {
+        for &(resource, limit) in limits.iter() {
+            if let Some(limit) = limit {
+                let rlimit = libc::rlimit {
+                    rlim_cur: limit as libc::rlim_t,
+                    rlim_max: limit as libc::rlim_t,
+                };
+                if unsafe { libc::setrlimit(resource, &rlimit) } != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+        }
+        if let Some(mask) = umask {
+            unsafe { libc::umask(mask as libc::mode_t) };
+        }
+        Ok(())
+    });
+    if !opts.sandbox {
+        run_as.switch(command);
+    }
+    Ok(())
+}
+
+/// Runs `f` in the child between fork and exec. Only async-signal-safe calls
+/// can be made there, so everything that allocates is prepared beforehand.
+pub fn before_exec<F>(command: &mut Command, f: F)
+    where F: FnMut() -> io::Result<()> + Send + Sync + 'static
+{
+    command.before_exec(f);
+}
+
+// Names are looked up before numeric IDs
+fn lookup_user(user: &str) -> Result<Account> {
+    let name = try!(c_string(user));
+    let mut passwd: libc::passwd = unsafe { ::std::mem::zeroed() };
+    let mut buf = vec![0 as libc::c_char; LOOKUP_BUFFER_SIZE];
+    let mut result = ptr::null_mut();
+    unsafe {
+        libc::getpwnam_r(name.as_ptr(), &mut passwd, buf.as_mut_ptr(), buf.len(), &mut result);
+    }
+    if result.is_null() {
+        if let Ok(uid) = user.parse::<libc::uid_t>() {
+            unsafe {
+                libc::getpwuid_r(uid, &mut passwd, buf.as_mut_ptr(), buf.len(), &mut result);
+            }
+        }
+    }
+    if result.is_null() {
+        return Err(Error::app(Reason::InvalidConf, format!("User {} not found", user)));
+    }
+    Ok(Account {
+        uid: passwd.pw_uid,
+        gid: passwd.pw_gid,
+        name: unsafe { CStr::from_ptr(passwd.pw_name) }.to_string_lossy().into_owned(),
+        home: unsafe { CStr::from_ptr(passwd.pw_dir) }.to_string_lossy().into_owned(),
+    })
+}
+
+fn lookup_group(group: &str) -> Result<libc::gid_t> {
+    let name = try!(c_string(group));
+    let mut grp: libc::group = unsafe { ::std::mem::zeroed() };
+    let mut buf = vec![0 as libc::c_char; LOOKUP_BUFFER_SIZE];
+    let mut result = ptr::null_mut();
+    unsafe {
+        libc::getgrnam_r(name.as_ptr(), &mut grp, buf.as_mut_ptr(), buf.len(), &mut result);
+    }
+    if !result.is_null() {
+        return Ok(grp.gr_gid);
+    }
+    group.parse::<libc::gid_t>()
+         .map_err(|_| Error::app(Reason::InvalidConf, format!("Group {} not found", group)))
+}
+
+fn c_string(s: &str) -> Result<CString> {
+    CString::new(s).map_err(|_| Error::app(Reason::InvalidConf, "Invalid user or group name"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RunAs, configure};
+    use std::env;
+    use std::fs;
+    use std::os::unix::fs::MetadataExt;
+    use std::process::Command;
+    use libc;
+    use conf::ExecOptions;
+
+    #[test]
+    fn scrubs_environment() {
+        env::set_var("KOUKKU_TEST_SECRET", "secret");
+        let mut opts = ExecOptions::default();
+        opts.env_allowlist = Some(vec!["PATH".to_owned()]);
+        let mut command = Command::new("env");
+
+        configure(&mut command, &opts).unwrap();
+        let output = String::from_utf8(command.output().unwrap().stdout).unwrap();
+
+        assert!(output.contains("PATH="));
+        assert!(!output.contains("KOUKKU_TEST_SECRET"));
+    }
+
+    #[test]
+    fn applies_umask_and_limits() {
+        let mut opts = ExecOptions::default();
+        opts.umask = Some(0o027);
+        opts.open_files_limit = Some(64);
+        let mut command = Command::new("sh");
+        command.arg("-c").arg("umask; ulimit -n");
+
+        configure(&mut command, &opts).unwrap();
+        let output = String::from_utf8(command.output().unwrap().stdout).unwrap();
+
+        assert_eq!(output, "0027\n64\n");
+    }
+
+    #[test]
+    fn creates_directories_owned_by_user() {
+        // Only root can give files away
+        if unsafe { libc::geteuid() } != 0 {
+            return;
+        }
+        let dir = env::temp_dir().join("koukku-privileges-test");
+        let _ = fs::remove_dir_all(&dir);
+        let mut opts = ExecOptions::default();
+        opts.user = Some("nobody".to_owned());
+        let run_as = RunAs::lookup(&opts).unwrap();
+
+        assert!(run_as.create_dir(&dir).unwrap());
+
+        let metadata = fs::metadata(&dir).unwrap();
+        assert_eq!((Some(metadata.uid()), Some(metadata.gid())), run_as.ids());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn creates_no_directory_without_user() {
+        let dir = env::temp_dir().join("koukku-privileges-no-user-test");
+        let _ = fs::remove_dir_all(&dir);
+
+        assert!(!RunAs::default().create_dir(&dir).unwrap());
+        assert!(!dir.exists());
+    }
+
+    #[test]
+    fn unknown_user_is_an_error() {
+        let mut opts = ExecOptions::default();
+        opts.user = Some("koukku-no-such-user".to_owned());
+
+        assert!(configure(&mut Command::new("true"), &opts).is_err());
+    }
+}
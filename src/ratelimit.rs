@@ -0,0 +1,186 @@
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use error::{Error, Result};
+
+// The least recently seen keys are forgotten when there are more than this
+const MAX_TRACKED_KEYS: usize = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Limit {
+    pub per_minute: u32,
+    pub burst: u32,
+}
+
+impl Limit {
+    fn per_second(&self) -> f64 {
+        self.per_minute as f64 / 60.0
+    }
+}
+
+struct TokenBucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn new(limit: &Limit, now: Instant) -> TokenBucket {
+        TokenBucket {
+            tokens: limit.burst as f64,
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, limit: &Limit, now: Instant) {
+        let elapsed = now.duration_since(self.updated);
+        let secs = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+        self.tokens = (self.tokens + secs * limit.per_second()).min(limit.burst as f64);
+        self.updated = now;
+    }
+
+    fn take(&mut self, limit: &Limit, now: Instant) -> ::std::result::Result<(), Duration> {
+        self.refill(limit, now);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else if limit.per_minute == 0 {
+            Err(Duration::from_secs(60))
+        } else {
+            let wait = ((1.0 - self.tokens) / limit.per_second()).ceil();
+            Err(Duration::from_secs(wait as u64))
+        }
+    }
+}
+
+struct Tracked {
+    bucket: TokenBucket,
+    // Sequence number of the last check of the key
+    seen: u64,
+}
+
+struct Keys<K> {
+    buckets: HashMap<K, Tracked>,
+    // Keys by the sequence number of their last check, oldest first
+    by_seen: BTreeMap<u64, K>,
+    checks: u64,
+}
+
+pub struct RateLimiter<K: Hash + Eq + Clone> {
+    keys: Mutex<Keys<K>>,
+}
+
+impl<K: Hash + Eq + Clone> RateLimiter<K> {
+    pub fn new() -> RateLimiter<K> {
+        RateLimiter {
+            keys: Mutex::new(Keys {
+                buckets: HashMap::new(),
+                by_seen: BTreeMap::new(),
+                checks: 0,
+            }),
+        }
+    }
+
+    /// Takes a token from the bucket of the given key.
+    /// Fails with `Error::RateLimited` when the bucket is empty.
+    pub fn check(&self, key: K, limit: &Limit) -> Result<()> {
+        self.check_at(key, limit, Instant::now())
+    }
+
+    fn check_at(&self, key: K, limit: &Limit, now: Instant) -> Result<()> {
+        let mut keys = try!(self.keys.lock());
+        let Keys { ref mut buckets, ref mut by_seen, ref mut checks } = *keys;
+        *checks += 1;
+        let result = {
+            let tracked = buckets.entry(key.clone()).or_insert_with(|| {
+                Tracked {
+                    bucket: TokenBucket::new(limit, now),
+                    seen: 0,
+                }
+            });
+            by_seen.remove(&tracked.seen);
+            tracked.seen = *checks;
+            tracked.bucket.take(limit, now)
+        };
+        by_seen.insert(*checks, key);
+        while buckets.len() > MAX_TRACKED_KEYS {
+            let oldest = match by_seen.keys().next() {
+                Some(&seen) => seen,
+                None => break,
+            };
+            if let Some(key) = by_seen.remove(&oldest) {
+                buckets.remove(&key);
+            }
+        }
+        result.map_err(Error::rate_limited)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Limit, RateLimiter, MAX_TRACKED_KEYS};
+    use std::time::{Duration, Instant};
+    use error::Error;
+
+    const LIMIT: Limit = Limit {
+        per_minute: 60,
+        burst: 2,
+    };
+
+    #[test]
+    fn allows_burst() {
+        let limiter = RateLimiter::new();
+        let now = Instant::now();
+
+        assert!(limiter.check_at("a", &LIMIT, now).is_ok());
+        assert!(limiter.check_at("a", &LIMIT, now).is_ok());
+        assert!(limiter.check_at("a", &LIMIT, now).is_err());
+    }
+
+    #[test]
+    fn keys_are_independent() {
+        let limiter = RateLimiter::new();
+        let now = Instant::now();
+
+        assert!(limiter.check_at("a", &LIMIT, now).is_ok());
+        assert!(limiter.check_at("a", &LIMIT, now).is_ok());
+        assert!(limiter.check_at("b", &LIMIT, now).is_ok());
+    }
+
+    #[test]
+    fn refills_over_time() {
+        let limiter = RateLimiter::new();
+        let now = Instant::now();
+
+        assert!(limiter.check_at("a", &LIMIT, now).is_ok());
+        assert!(limiter.check_at("a", &LIMIT, now).is_ok());
+        match limiter.check_at("a", &LIMIT, now) {
+            Err(Error::RateLimited(_, wait)) => assert_eq!(wait, 1),
+            other => panic!("Expected rate limit error, but got {:?}", other),
+        }
+        assert!(limiter.check_at("a", &LIMIT, now + Duration::from_secs(1)).is_ok());
+    }
+
+    #[test]
+    fn forgets_least_recently_seen_keys() {
+        let limiter = RateLimiter::new();
+        let now = Instant::now();
+        assert!(limiter.check_at(0, &LIMIT, now).is_ok());
+        assert!(limiter.check_at(1, &LIMIT, now).is_ok());
+        assert!(limiter.check_at(1, &LIMIT, now).is_ok());
+
+        for key in 2..MAX_TRACKED_KEYS + 1 {
+            assert!(limiter.check_at(key, &LIMIT, now).is_ok());
+            if key == 2 {
+                assert!(limiter.check_at(0, &LIMIT, now).is_ok());
+            }
+        }
+
+        let keys = limiter.keys.lock().unwrap();
+        assert_eq!(keys.buckets.len(), MAX_TRACKED_KEYS);
+        assert_eq!(keys.by_seen.len(), MAX_TRACKED_KEYS);
+        assert!(keys.buckets.contains_key(&0));
+        assert!(!keys.buckets.contains_key(&1));
+    }
+}
@@ -0,0 +1,106 @@
+use std::collections::{HashSet, VecDeque};
+use std::sync::Mutex;
+
+use error::{Reason, Result, Error};
+
+/// How far the timestamp of a signed request may be from the server clock, in seconds
+pub const MAX_CLOCK_SKEW: u64 = 300;
+
+// Nonces are random hex strings, so longer ones are rejected
+const MAX_NONCE_LENGTH: usize = 64;
+
+struct Seen {
+    nonces: HashSet<String>,
+    // Nonces in the order they were seen, with the time they can be forgotten
+    expiry: VecDeque<(u64, String)>,
+}
+
+/// Remembers the nonces of recently signed requests, so that a captured
+/// request can't be replayed.
+pub struct ReplayGuard {
+    seen: Mutex<Seen>,
+}
+
+impl ReplayGuard {
+    pub fn new() -> ReplayGuard {
+        ReplayGuard {
+            seen: Mutex::new(Seen {
+                nonces: HashSet::new(),
+                expiry: VecDeque::new(),
+            }),
+        }
+    }
+
+    /// Accepts a request signed at `timestamp` with `nonce` once, if the
+    /// timestamp is within `MAX_CLOCK_SKEW` seconds of `now`.
+    pub fn check(&self, nonce: &str, timestamp: u64, now: u64) -> Result<()> {
+        if nonce.is_empty() || nonce.len() > MAX_NONCE_LENGTH {
+            return Err(Error::app(Reason::MissingFields, "Invalid nonce"));
+        }
+        if timestamp.saturating_add(MAX_CLOCK_SKEW) < now ||
+           timestamp > now.saturating_add(MAX_CLOCK_SKEW) {
+            let msg = format!("Request timestamp {} is too far from the server time {}",
+                              timestamp,
+                              now);
+            return Err(Error::app(Reason::ReplayedRequest, msg));
+        }
+        let mut seen = try!(self.seen.lock());
+        // A nonce is accepted with timestamps up to now + MAX_CLOCK_SKEW, which
+        // stop being accepted MAX_CLOCK_SKEW later, so it's kept for twice as long.
+        while seen.expiry.front().map_or(false, |&(expires, _)| expires < now) {
+            if let Some((_, old)) = seen.expiry.pop_front() {
+                seen.nonces.remove(&old);
+            }
+        }
+        if !seen.nonces.insert(nonce.to_owned()) {
+            return Err(Error::app(Reason::ReplayedRequest, "Request has already been used"));
+        }
+        seen.expiry.push_back((now.saturating_add(2 * MAX_CLOCK_SKEW), nonce.to_owned()));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ReplayGuard, MAX_CLOCK_SKEW};
+    use error::{Error, Reason};
+
+    const NOW: u64 = 1_500_000_000;
+
+    fn is_replayed(result: ::error::Result<()>) -> bool {
+        match result {
+            Err(Error::App(Reason::ReplayedRequest, _)) => true,
+            _ => false,
+        }
+    }
+
+    #[test]
+    fn rejects_reused_nonce() {
+        let guard = ReplayGuard::new();
+
+        assert!(guard.check("abc", NOW, NOW).is_ok());
+        assert!(is_replayed(guard.check("abc", NOW, NOW + 1)));
+        assert!(guard.check("def", NOW, NOW + 1).is_ok());
+    }
+
+    #[test]
+    fn rejects_old_and_future_timestamps() {
+        let guard = ReplayGuard::new();
+
+        assert!(is_replayed(guard.check("a", NOW - MAX_CLOCK_SKEW - 1, NOW)));
+        assert!(is_replayed(guard.check("b", NOW + MAX_CLOCK_SKEW + 1, NOW)));
+        assert!(guard.check("c", NOW - MAX_CLOCK_SKEW, NOW).is_ok());
+        assert!(is_replayed(guard.check("d", u64::max_value(), NOW)));
+        assert!(is_replayed(guard.check("e", NOW, u64::max_value())));
+    }
+
+    #[test]
+    fn forgets_expired_nonces() {
+        let guard = ReplayGuard::new();
+
+        assert!(guard.check("abc", NOW, NOW).is_ok());
+        assert!(guard.check("def", NOW + 3 * MAX_CLOCK_SKEW, NOW + 3 * MAX_CLOCK_SKEW).is_ok());
+
+        assert_eq!(guard.seen.lock().unwrap().nonces.len(), 1);
+    }
+}
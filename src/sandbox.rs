@@ -0,0 +1,235 @@
+use std::ffi::CString;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::ptr;
+use libc::{self, c_ulong};
+
+use conf::ExecOptions;
+use error::{Reason, Result, Error};
+use privileges::{self, RunAs};
+
+const MOUNTINFO: &'static str = "/proc/self/mountinfo";
+
+// util-linux program that starts the command in the PID namespace
+const UNSHARE: &'static str = "unshare";
+
+// Per mount point flags that a read-only remount has to keep
+const KEPT_FLAGS: &'static [(&'static str, c_ulong)] = &[("nosuid", libc::MS_NOSUID),
+                                                       ("nodev", libc::MS_NODEV),
+                                                       ("noexec", libc::MS_NOEXEC),
+                                                       ("noatime", libc::MS_NOATIME),
+                                                       ("nodiratime", libc::MS_NODIRATIME),
+                                                       ("relatime", libc::MS_RELATIME)];
+
+struct MountPoint {
+    path: CString,
+    flags: c_ulong,
+}
+
+/// Creates a command that runs the program in new mount, PID and network namespaces,
+/// where only the working directory and the writable paths of the project can be written to.
+///
+/// The mount and network namespaces are set up before exec. The PID namespace only
+/// applies to children of the process that enters it, so the program is started by
+/// `unshare`, which forks into the namespace, mounts `/proc` and drops privileges.
+pub fn command(program: &str, dir: &Path, opts: &ExecOptions) -> Result<Command> {
+    if unsafe { libc::geteuid() } != 0 {
+        return Err(Error::app(Reason::InvalidConf,
+                              "Sandboxed commands require running koukku as root"));
+    }
+    let mut writable = vec![try!(fs::canonicalize(dir))];
+    for path in &opts.writable_paths {
+        writable.push(try!(fs::canonicalize(path)));
+    }
+    let mut text = String::new();
+    let _ = try!(File::open(MOUNTINFO).and_then(|mut f| f.read_to_string(&mut text)));
+    let read_only = try!(parse_mountinfo(&text, &writable)
+                             .into_iter()
+                             .map(|(path, flags)| {
+                                 c_path(&path).map(|path| {
+                                     MountPoint {
+                                         path: path,
+                                         flags: flags,
+                                     }
+                                 })
+                             })
+                             .collect::<Result<Vec<_>>>());
+    let writable = try!(writable.iter().map(|p| c_path(p)).collect::<Result<Vec<_>>>());
+    let workdir = try!(c_path(dir));
+    let root = CString::new("/").unwrap();
+
+    let mut command = Command::new(UNSHARE);
+    command.arg("--pid").arg("--fork").arg("--kill-child").arg("--mount-proc");
+    let (uid, gid) = try!(RunAs::lookup(opts)).ids();
+    let uid = match uid {
+        Some(uid) if uid != 0 => uid,
+        _ => {
+            return Err(Error::app(Reason::InvalidConf,
+                                  "Sandboxed commands must run as a user other than root"))
+        }
+    };
+    if let Some(gid) = gid {
+        command.arg("--setgid").arg(gid.to_string());
+    }
+    command.arg("--setuid").arg(uid.to_string());
+    command.arg("--").arg(program);
+
+    privileges::before_exec(&mut command, move || {
+        try!(check(unsafe { libc::unshare(libc::CLONE_NEWNS | libc::CLONE_NEWNET) }));
+        // Keep the mounts below from propagating back to the host
+        try!(mount(None, &root, None, libc::MS_REC | libc::MS_PRIVATE));
+        for path in &writable {
+            try!(mount(Some(path), path, None, libc::MS_BIND | libc::MS_REC));
+        }
+        for point in &read_only {
+            let flags = libc::MS_BIND | libc::MS_REMOUNT | libc::MS_RDONLY | point.flags;
+            match mount(None, &point.path, None, flags) {
+                Err(ref err) if err.raw_os_error() == Some(libc::ENOENT) => (),
+                result => try!(result),
+            }
+        }
+        // The working directory was entered before the writable bind mount
+        check(unsafe { libc::chdir(workdir.as_ptr()) })
+    });
+    Ok(command)
+}
+
+// Mount points to remount read-only with the flags they need to keep.
+// Mounts inside the writable paths are left alone.
+fn parse_mountinfo(text: &str, writable: &[PathBuf]) -> Vec<(PathBuf, c_ulong)> {
+    text.lines()
+        .filter_map(|line| {
+            let fields = line.split(' ').collect::<Vec<_>>();
+            if fields.len() < 6 {
+                return None;
+            }
+            let path = PathBuf::from(unescape(fields[4]));
+            if writable.iter().any(|w| path.starts_with(w)) {
+                return None;
+            }
+            let flags = fields[5].split(',')
+                                 .filter_map(|opt| {
+                                     KEPT_FLAGS.iter()
+                                               .find(|&&(name, _)| name == opt)
+                                               .map(|&(_, flag)| flag)
+                                 })
+                                 .fold(0, |acc, flag| acc | flag);
+            Some((path, flags))
+        })
+        .collect()
+}
+
+// Spaces and other special characters are escaped as octal, e.g. `\040`
+fn unescape(field: &str) -> String {
+    let mut result = String::new();
+    let mut rest = field;
+    while let Some(pos) = rest.find('\\') {
+        result.push_str(&rest[..pos]);
+        let code = if rest.is_char_boundary(pos + 4) {
+            u8::from_str_radix(&rest[pos + 1..pos + 4], 8).ok()
+        } else {
+            None
+        };
+        match code {
+            Some(c) => {
+                result.push(c as char);
+                rest = &rest[pos + 4..];
+            }
+            None => {
+                result.push('\\');
+                rest = &rest[pos + 1..];
+            }
+        }
+    }
+    result.push_str(rest);
+    result
+}
+
+fn mount(source: Option<&CString>,
+         target: &CString,
+         fstype: Option<&CString>,
+         flags: c_ulong)
+         -> io::Result<()> {
+    check(unsafe {
+        libc::mount(source.map(|s| s.as_ptr()).unwrap_or(ptr::null()),
+                    target.as_ptr(),
+                    fstype.map(|s| s.as_ptr()).unwrap_or(ptr::null()),
+                    flags,
+                    ptr::null())
+    })
+}
+
+fn check(ret: libc::c_int) -> io::Result<()> {
+    if ret == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+fn c_path(path: &Path) -> Result<CString> {
+    CString::new(path.as_os_str().as_bytes())
+        .map_err(|_| Error::app(Reason::InvalidPath, "Invalid sandbox path"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{command, parse_mountinfo, unescape};
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+    use libc;
+    use conf::ExecOptions;
+
+    const MOUNTINFO: &'static str = "\
+        22 1 8:1 / / rw,relatime shared:1 - ext4 /dev/sda1 rw\n\
+        23 22 0:5 / /dev rw,nosuid,relatime shared:2 - devtmpfs udev rw\n\
+        24 22 8:2 / /srv/my\\040sites rw,nodev,noexec shared:3 - ext4 /dev/sda2 rw\n\
+        25 24 8:3 / /srv/my\\040sites/site/cache rw shared:4 - tmpfs tmpfs rw\n";
+
+    #[test]
+    fn skips_writable_mounts_and_keeps_flags() {
+        let writable = vec![PathBuf::from("/srv/my sites/site")];
+
+        let mounts = parse_mountinfo(MOUNTINFO, &writable);
+
+        assert_eq!(mounts,
+                   vec![(PathBuf::from("/"), libc::MS_RELATIME),
+                        (PathBuf::from("/dev"), libc::MS_NOSUID | libc::MS_RELATIME),
+                        (PathBuf::from("/srv/my sites"), libc::MS_NODEV | libc::MS_NOEXEC)]);
+    }
+
+    #[test]
+    fn unescapes_octal_characters() {
+        assert_eq!(unescape("/a\\040b\\134c"), "/a b\\c");
+        assert_eq!(unescape("/plain"), "/plain");
+    }
+
+    #[test]
+    fn collects_large_output() {
+        // Namespaces can only be created by root
+        if unsafe { libc::geteuid() } != 0 {
+            return;
+        }
+        let dir = env::temp_dir().join("koukku-sandbox-test");
+        fs::create_dir_all(&dir).unwrap();
+        let opts = ExecOptions {
+            user: Some("nobody".to_owned()),
+            sandbox: true,
+            ..ExecOptions::default()
+        };
+        let mut sandboxed = command("sh", &dir, &opts).unwrap();
+        sandboxed.current_dir(&dir).arg("-c").arg("echo $$; head -c 200000 /dev/zero");
+
+        let output = sandboxed.output().unwrap();
+
+        assert!(output.status.success());
+        assert_eq!(&output.stdout[..2], b"1\n");
+        assert_eq!(output.stdout.len(), 200002);
+        let as_root = ExecOptions { user: None, ..opts };
+        assert!(command("sh", &dir, &as_root).is_err());
+    }
+}
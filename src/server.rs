@@ -1,80 +1,415 @@
-use std::sync::Mutex;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
 use std::sync::mpsc::Sender;
 use std::io::Read;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
+use std::time::{SystemTime, UNIX_EPOCH};
 use hyper;
 use hyper::uri::RequestUri;
 use hyper::Server;
+use hyper::method::Method;
+use hyper::header::{ContentLength, ContentType};
+use hyper::mime::{Mime, TopLevel, SubLevel};
+use hyper::status::StatusCode;
 use hyper::server::{Request, Response, Listening, Handler};
 use hyper::error::Result as HyperResult;
 use openssl::crypto::hmac::hmac;
 use openssl::crypto::memcmp;
 use rustc_serialize::hex::ToHex;
+use serde_json::Value as JsonValue;
 
-use payload;
-use conf::{Projects, Project};
+use payload::{self, json_object, json_str};
+use conf::{self, Conf, Projects, Project};
 use error::{Reason, Error, Result};
+use git;
 use header;
 use header::{GithubEvent, HubSignature};
+use history::History;
+use job::{Job, Trigger};
+use logging;
+use ratelimit::{Limit, RateLimiter};
+use replay::ReplayGuard;
+use status::Status;
+
+enum Reply {
+    Json(StatusCode, JsonValue),
+    Text(StatusCode, String),
+}
+
+/// Signed actions that can be posted to /projects/<id>/<action>
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Action {
+    Trigger,
+    Rollback,
+}
+
+#[derive(Debug, PartialEq)]
+enum Outcome {
+    Pong,
+    Triggered,
+    Ignored(String),
+}
+
+impl Outcome {
+    fn status(&self) -> StatusCode {
+        match *self {
+            Outcome::Ignored(_) => StatusCode::Accepted,
+            _ => StatusCode::Ok,
+        }
+    }
+
+    fn to_json(&self) -> JsonValue {
+        match *self {
+            Outcome::Pong => json_object(vec![("status", json_str("pong"))]),
+            Outcome::Triggered => json_object(vec![("status", json_str("triggered"))]),
+            Outcome::Ignored(ref reason) => {
+                json_object(vec![("status", json_str("ignored")), ("reason", json_str(reason))])
+            }
+        }
+    }
+}
 
 struct WebhookHandler {
     pub projects: Projects,
-    pub send: Mutex<Sender<String>>,
+    pub routes: HashMap<String, String>,
+    pub send: Mutex<Sender<Job>>,
+    pub max_body_size: u64,
+    pub ip_limit: Option<Limit>,
+    pub ready_queue_size: usize,
+    ip_limiter: RateLimiter<IpAddr>,
+    project_limiter: RateLimiter<String>,
+    replay_guard: ReplayGuard,
+    status: Arc<Status>,
+    history: History,
 }
 
 impl WebhookHandler {
-    fn new(projects: Projects, send: Sender<String>) -> WebhookHandler {
+    fn new(conf: &Conf, send: Sender<Job>, status: Arc<Status>) -> WebhookHandler {
+        let routes = conf.projects
+                         .values()
+                         .filter_map(|p| p.path.clone().map(|path| (path, p.id.to_owned())))
+                         .collect();
         WebhookHandler {
-            projects: projects,
+            projects: conf.projects.clone(),
+            routes: routes,
             send: Mutex::new(send),
+            max_body_size: conf.max_body_size,
+            ip_limit: conf.ip_limit,
+            ready_queue_size: conf.ready_queue_size,
+            ip_limiter: RateLimiter::new(),
+            project_limiter: RateLimiter::new(),
+            replay_guard: ReplayGuard::new(),
+            status: status,
+            history: History::new(&conf.location),
         }
     }
 
-    fn ping(&self) -> Result<String> {
-        Ok("Pong".to_owned())
+    // Built-in endpoints are served before any webhook processing
+    fn builtin_endpoint(&self, req: &Request) -> Option<Reply> {
+        if req.method != Method::Get {
+            return None;
+        }
+        match request_path(&req.uri) {
+            Ok(ref path) if path == "/healthz" => Some(health()),
+            Ok(ref path) if path == "/readyz" => Some(self.readiness()),
+            Ok(ref path) if path == "/version" => Some(version()),
+            Ok(ref path) if path == "/metrics" => {
+                Some(Reply::Text(StatusCode::Ok, self.status.render_metrics()))
+            }
+            _ => None,
+        }
+    }
+
+    fn readiness(&self) -> Reply {
+        let alive = self.status.is_executor_alive();
+        let queue_depth = self.status.queue_depth();
+        let ready = alive && queue_depth < self.ready_queue_size;
+        let (status, text) = if ready {
+            (StatusCode::Ok, "ready")
+        } else {
+            (StatusCode::ServiceUnavailable, "not_ready")
+        };
+        let json = json_object(vec![("status", json_str(text)),
+                                    ("executor_alive", JsonValue::Bool(alive)),
+                                    ("queue_depth", JsonValue::U64(queue_depth as u64))]);
+        Reply::Json(status, json)
+    }
+
+    fn ping(&self) -> Result<Outcome> {
+        Ok(Outcome::Pong)
     }
 
+    // Projects with a path can only be reached through that path. Other
+    // projects are looked up using the repository name in the payload.
     fn get_project(&self, repo: &str) -> Result<&Project> {
         self.projects
-            .get(repo)
+            .values()
+            .find(|p| p.path.is_none() && p.repo == repo)
             .ok_or(Error::app(Reason::MissingProject, "No project found!"))
     }
 
-    fn push(&self, readable: &mut Read, signature: &HubSignature) -> Result<String> {
+    // Only the root path falls back to looking up the project by repository,
+    // so requests to unknown paths are rejected before the body is read
+    fn route(&self, path: &str) -> Result<Option<&Project>> {
+        match self.routes.get(path).and_then(|id| self.projects.get(id)) {
+            Some(project) => Ok(Some(project)),
+            None if path == "/" && self.projects.values().any(|p| p.path.is_none()) => Ok(None),
+            None => {
+                let msg = format!("No project found for path {}", path);
+                Err(Error::app(Reason::MissingProject, msg))
+            }
+        }
+    }
+
+    fn push(&self,
+            route: Option<&Project>,
+            delivery: Option<String>,
+            readable: &mut Read,
+            signature: &HubSignature)
+            -> Result<Outcome> {
         // Body
-        let bytes = try!(read_bytes(readable));
+        let bytes = try!(read_bytes(readable, self.max_body_size));
         let json = try!(payload::bytes_to_json(&bytes));
-        let repo = try!(payload::get_repo_name(&json));
-        let branch = try!(payload::get_branch(&json));
 
         // Project
-        let project = try!(self.get_project(repo));
+        let project = match route {
+            Some(project) => try!(check_repo(project, &json)),
+            None => try!(self.get_project(try!(payload::get_repo_name(&json)))),
+        };
+        let repo = &project.repo;
+        let branch = try!(payload::get_branch(&json));
+        let commit = payload::get_commit(&json).map(|c| c.to_owned());
+        logging::update_context(|c| {
+            c.project = Some(project.id.to_owned());
+            c.repo = Some(repo.to_owned());
+            c.commit = commit.clone();
+        });
 
         // Verify
         let _ = try!(verify(&signature, project.key.as_ref(), &bytes));
         if branch != project.branch {
-            let error_msg = format!("Expected branch {} but got {}", project.branch, branch);
-            return Err(Error::app(Reason::InvalidBranch, error_msg));
+            info!("Ignored push to branch {} in repo {}: tracking branch {}",
+                  branch,
+                  repo,
+                  project.branch);
+            self.status.metrics.ignored(&project.id);
+            return Ok(Outcome::Ignored("untracked_branch".to_owned()));
         }
+        let _ = try!(self.check_project_limit(project));
 
         // Trigger
-        let _ = try!(self.trigger_hook(repo));
+        let mut job = Job::new(&project.id, Trigger::Webhook);
+        job.delivery = delivery;
+        job.commit = commit;
+        logging::update_context(|c| c.job = Some(job.id.to_owned()));
+        let _ = try!(self.trigger_hook(job));
         info!("Triggered hook for repo: {}", repo);
 
-        Ok("Hook triggered".to_owned())
+        Ok(Outcome::Triggered)
+    }
+
+    fn manual_trigger(&self, id: &str, action: Action, req: &mut Request) -> Result<Outcome> {
+        let project = try!(self.projects
+                               .get(id)
+                               .ok_or(Error::app(Reason::MissingProject, "No project found!")));
+        let _ = try!(check_content_length(req, self.max_body_size));
+        let signature = try!(header::get_signature(&req.headers));
+        self.trigger(project, action, req, &signature)
+    }
+
+    // Manual triggers are signed with the project key just like GitHub
+    // webhooks, and carry a timestamp and a nonce so that they can't be replayed.
+    // The body may override the branch or the commit to deploy.
+    // Rollbacks deploy the commit given in `to`, or the previous successful deploy.
+    fn trigger(&self,
+               project: &Project,
+               action: Action,
+               readable: &mut Read,
+               signature: &HubSignature)
+               -> Result<Outcome> {
+        let bytes = try!(read_bytes(readable, self.max_body_size));
+        let _ = try!(verify(&signature, project.key.as_ref(), &bytes));
+        let json = try!(payload::bytes_to_json(&bytes));
+        logging::update_context(|c| {
+            c.project = Some(project.id.to_owned());
+            c.repo = Some(project.repo.to_owned());
+        });
+        let _ = try!(self.check_replay(&json));
+        let _ = try!(self.check_project_limit(project));
+
+        let job = match action {
+            Action::Trigger => {
+                let mut job = Job::new(&project.id, Trigger::Manual);
+                job.branch = payload::get_string(&json, "branch").map(|s| s.to_owned());
+                job.revision = payload::get_string(&json, "commit").map(|s| s.to_owned());
+                for rev in job.branch.iter().chain(job.revision.iter()) {
+                    try!(git::check_revision(rev));
+                }
+                job
+            }
+            Action::Rollback => {
+                let to = payload::get_string(&json, "to");
+                let target = try!(self.history.rollback_target(&project.id, to));
+                info!("Rolling back project {} to {}", project.id, target);
+                let mut job = Job::new(&project.id, Trigger::Rollback);
+                job.revision = Some(target);
+                job
+            }
+        };
+        logging::update_context(|c| c.job = Some(job.id.to_owned()));
+        let _ = try!(self.trigger_hook(job));
+        info!("Manually triggered update for project: {}", project.id);
+
+        Ok(Outcome::Triggered)
+    }
+
+    fn dispatch(&self, route: Option<&Project>, req: &mut Request) -> Result<Outcome> {
+        let event = try!(header::get_event(&req.headers));
+        self.status.metrics.delivery(event.name());
+        match event {
+            GithubEvent::Ping => self.ping(),
+            GithubEvent::Push => {
+                let _ = try!(check_content_length(req, self.max_body_size));
+                let signature = try!(header::get_signature(&req.headers));
+                let delivery = header::get_delivery(&req.headers);
+                self.push(route, delivery, req, &signature)
+            }
+        }
+    }
+
+    fn check_replay(&self, json: &JsonValue) -> Result<()> {
+        let timestamp = try!(json.lookup("timestamp")
+                                 .and_then(|v| v.as_u64())
+                                 .ok_or(Error::app(Reason::MissingFields, "No timestamp found")));
+        let nonce = try!(payload::get_string(json, "nonce")
+                             .ok_or(Error::app(Reason::MissingFields, "No nonce found")));
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        self.replay_guard.check(nonce, timestamp, now)
+    }
+
+    fn check_ip_limit(&self, remote_addr: &SocketAddr) -> Result<()> {
+        match self.ip_limit {
+            Some(ref limit) => {
+                self.ip_limiter
+                    .check(remote_addr.ip(), limit)
+                    .map_err(|err| self.rate_limited("ip", err))
+            }
+            None => Ok(()),
+        }
+    }
+
+    fn check_project_limit(&self, project: &Project) -> Result<()> {
+        match project.limit {
+            Some(ref limit) => {
+                self.project_limiter
+                    .check(project.id.clone(), limit)
+                    .map_err(|err| self.rate_limited("project", err))
+            }
+            None => Ok(()),
+        }
+    }
+
+    fn rate_limited(&self, scope: &str, err: Error) -> Error {
+        self.status.metrics.rate_limited(scope);
+        err
     }
 
-    fn trigger_hook(&self, repo: &str) -> Result<()> {
+    fn record_failure(&self, err: &Error) {
+        let metrics = &self.status.metrics;
+        metrics.failure(err.code());
+        if let Error::App(Reason::InvalidSignature, _) = *err {
+            metrics.signature_failure();
+        }
+    }
+
+    fn trigger_hook(&self, job: Job) -> Result<()> {
         let s = try!(self.send.lock());
-        let _ = try!(s.send(repo.to_owned()));
-        Ok(())
+        // Count the job before sending so that the executor never sees a negative queue
+        self.status.job_queued();
+        s.send(job).map_err(|err| {
+            self.status.job_dequeued();
+            Error::from(err)
+        })
+    }
+}
+
+// Manual triggers are posted to /projects/<id>/trigger and
+// rollbacks to /projects/<id>/rollback
+fn trigger_target(method: &Method, path: &str) -> Option<(String, Action)> {
+    let parts = path.split('/').collect::<Vec<_>>();
+    if *method != Method::Post || parts.len() != 4 || parts[1] != "projects" {
+        return None;
+    }
+    let action = match parts[3] {
+        "trigger" => Action::Trigger,
+        "rollback" => Action::Rollback,
+        _ => return None,
+    };
+    Some((parts[2].to_owned(), action))
+}
+
+fn health() -> Reply {
+    Reply::Json(StatusCode::Ok, json_object(vec![("status", json_str("ok"))]))
+}
+
+fn version() -> Reply {
+    let features = features().into_iter().map(json_str).collect();
+    let json = json_object(vec![("version", json_str(env!("CARGO_PKG_VERSION"))),
+                                ("features", JsonValue::Array(features))]);
+    Reply::Json(StatusCode::Ok, json)
+}
+
+// Optional Cargo features compiled into this build
+fn features() -> Vec<&'static str> {
+    let mut features = Vec::new();
+    if cfg!(feature = "libgit2") {
+        features.push("libgit2");
     }
+    features
 }
 
-fn read_bytes(read: &mut Read) -> Result<Vec<u8>> {
+// Payloads without a repository name are accepted for routed projects
+fn check_repo<'a>(project: &'a Project, json: &JsonValue) -> Result<&'a Project> {
+    match payload::get_repo_name(json) {
+        Ok(repo) if repo != project.repo => {
+            let msg = format!("Repository {} does not match project {}", repo, project.id);
+            Err(Error::app(Reason::MissingProject, msg))
+        }
+        _ => Ok(project),
+    }
+}
+
+fn request_path(uri: &RequestUri) -> Result<String> {
+    match *uri {
+        RequestUri::AbsolutePath(ref s) => {
+            let path = s.splitn(2, '?').next().unwrap_or("/");
+            conf::normalize_path(path).map_err(|err| Error::app(Reason::InvalidPath, err))
+        }
+        _ => Err(Error::app(Reason::InvalidPath, "Unsupported request URI")),
+    }
+}
+
+fn read_bytes(read: &mut Read, limit: u64) -> Result<Vec<u8>> {
     let mut buffer = Vec::new();
-    let _ = try!(read.read_to_end(&mut buffer));
-    Ok(buffer)
+    // Read one byte past the limit to detect bodies that are too large
+    let _ = try!(read.take(limit + 1).read_to_end(&mut buffer));
+    if buffer.len() as u64 > limit {
+        Err(too_large(limit))
+    } else {
+        Ok(buffer)
+    }
+}
+
+fn check_content_length(req: &Request, limit: u64) -> Result<()> {
+    match req.headers.get::<ContentLength>() {
+        Some(&ContentLength(length)) if length > limit => Err(too_large(limit)),
+        _ => Ok(()),
+    }
+}
+
+fn too_large(limit: u64) -> Error {
+    let msg = format!("Request body exceeds the limit of {} bytes", limit);
+    Error::app(Reason::PayloadTooLarge, msg)
 }
 
 fn verify(signature: &HubSignature, key: &[u8], content: &[u8]) -> Result<()> {
@@ -93,33 +428,111 @@ fn verify(signature: &HubSignature, key: &[u8], content: &[u8]) -> Result<()> {
 
 impl Handler for WebhookHandler {
     fn handle(&self, mut req: Request, res: Response) {
+        if let Some(reply) = self.builtin_endpoint(&req) {
+            return send_reply(res, reply);
+        }
         let remote_addr = &req.remote_addr.to_owned();
         let uri = &req.uri.to_owned();
-        let result = match header::get_event(&req.headers) {
-            Ok(GithubEvent::Ping) => self.ping(),
-            Ok(GithubEvent::Push) => {
-                header::get_signature(&req.headers).and_then(|sig| self.push(&mut req, &sig))
-            }
-            Err(err) => Err(err),
-        };
+        logging::set_context(logging::Context {
+            delivery: header::get_delivery(&req.headers),
+            ..Default::default()
+        });
+        let result = self.check_ip_limit(remote_addr)
+                         .and_then(|_| request_path(uri))
+                         .and_then(|path| {
+                             match trigger_target(&req.method, &path) {
+                                 Some((id, action)) => {
+                                     self.manual_trigger(&id, action, &mut req)
+                                 }
+                                 None => {
+                                     self.route(&path)
+                                         .and_then(|route| self.dispatch(route, &mut req))
+                                 }
+                             }
+                         });
+        if let Err(ref err) = result {
+            self.record_failure(err);
+        }
         handle_result(result, res, remote_addr, uri);
+        logging::clear_context();
     }
 }
 
-fn handle_result(result: Result<String>,
-                 response: Response,
+fn handle_result(result: Result<Outcome>,
+                 mut response: Response,
                  remote_addr: &SocketAddr,
                  uri: &RequestUri) {
     match result {
-        Ok(contents) => send_bytes(response, &contents.into_bytes()),
+        Ok(outcome) => {
+            *response.status_mut() = outcome.status();
+            send_json(response, &outcome.to_json())
+        }
         Err(err) => handle_error(err, response, remote_addr, uri),
     }
 }
 
 fn handle_error(err: Error, mut response: Response, remote_addr: &SocketAddr, uri: &RequestUri) {
     log_error(&err, remote_addr, uri);
-    *response.status_mut() = hyper::BadRequest;
-    send_bytes(response, b"Failed to trigger an update")
+    *response.status_mut() = error_status(&err);
+    if let Error::RateLimited(_, retry_after) = err {
+        response.headers_mut().set_raw("Retry-After", vec![retry_after.to_string().into_bytes()]);
+    }
+    send_json(response, &error_json(&err))
+}
+
+fn error_status(err: &Error) -> StatusCode {
+    match *err {
+        Error::App(ref reason, _) => reason_status(reason),
+        Error::RateLimited(..) => StatusCode::TooManyRequests,
+        Error::Mutex(_) | Error::Channel(_) | Error::Ini(_) | Error::CommandExit(..) => {
+            StatusCode::InternalServerError
+        }
+        Error::Hyper(_) | Error::Utf8(_) | Error::Io(_) | Error::Hex(_) | Error::Json(_) => {
+            StatusCode::BadRequest
+        }
+        #[cfg(feature = "libgit2")]
+        Error::Git(_) => StatusCode::InternalServerError,
+    }
+}
+
+fn reason_status(reason: &Reason) -> StatusCode {
+    match *reason {
+        Reason::InvalidSignature | Reason::ReplayedRequest => StatusCode::Forbidden,
+        Reason::MissingProject | Reason::MissingRevision => StatusCode::NotFound,
+        Reason::PayloadTooLarge => StatusCode::PayloadTooLarge,
+        Reason::MissingHeader | Reason::MissingFields | Reason::InvalidPath |
+        Reason::InvalidRevision => StatusCode::BadRequest,
+        Reason::InvalidConf | Reason::InvalidRepository | Reason::CommandFailed => {
+            StatusCode::InternalServerError
+        }
+    }
+}
+
+// The error message is left out on purpose: it may contain details such as
+// the expected signature.
+fn error_json(err: &Error) -> JsonValue {
+    json_object(vec![("status", json_str("error")), ("code", json_str(err.code()))])
+}
+
+fn send_reply(mut response: Response, reply: Reply) {
+    match reply {
+        Reply::Json(status, json) => {
+            *response.status_mut() = status;
+            send_json(response, &json)
+        }
+        Reply::Text(status, text) => {
+            *response.status_mut() = status;
+            response.headers_mut()
+                    .set(ContentType(Mime(TopLevel::Text, SubLevel::Plain, vec![])));
+            send_bytes(response, text.as_bytes())
+        }
+    }
+}
+
+fn send_json(mut response: Response, json: &JsonValue) {
+    response.headers_mut()
+            .set(ContentType(Mime(TopLevel::Application, SubLevel::Json, vec![])));
+    send_bytes(response, payload::json_to_string(json).as_bytes())
 }
 
 fn send_bytes(response: Response, bs: &[u8]) {
@@ -129,17 +542,20 @@ fn send_bytes(response: Response, bs: &[u8]) {
 }
 
 fn log_error(err: &Error, remote_addr: &SocketAddr, uri: &RequestUri) {
-    error!("Failed request from {} to {}: {}", remote_addr, uri, err)
+    match *err {
+        Error::RateLimited(..) => {
+            warn!("Rate limited request from {} to {}: {}", remote_addr, uri, err)
+        }
+        _ => error!("Failed request from {} to {}: {}", remote_addr, uri, err),
+    }
 }
 
-pub fn start(address: &str,
-             threads: Option<usize>,
-             projects: Projects,
-             send: Sender<String>)
-             -> HyperResult<Listening> {
-    let server = try!(Server::http(address));
-    let handler = WebhookHandler::new(projects, send);
-    match threads {
+pub fn start(conf: &Conf, send: Sender<Job>, status: Arc<Status>) -> HyperResult<Listening> {
+    let mut server = try!(Server::http(&conf.server[..]));
+    server.set_read_timeout(Some(conf.read_timeout));
+    server.set_write_timeout(Some(conf.write_timeout));
+    let handler = WebhookHandler::new(conf, send, status);
+    match conf.threads {
         None => server.handle(handler),
         Some(t) => server.handle_threads(handler, t),
     }
@@ -147,16 +563,27 @@ pub fn start(address: &str,
 
 #[cfg(test)]
 mod tests {
-    use super::WebhookHandler;
-    use std::collections::HashMap;
+    use super::{WebhookHandler, Action, Outcome, Reply, error_status, error_json, request_path,
+                trigger_target};
+    use hyper::method::Method;
+    use hyper::uri::RequestUri;
+    use hyper::status::StatusCode;
+    use std::sync::Arc;
     use std::sync::mpsc::{channel, Receiver};
     use std::io::Cursor;
+    use std::iter;
     use std::fmt::Debug;
+    use std::time::{SystemTime, UNIX_EPOCH};
     use openssl::crypto::hash::Type;
+    use openssl::crypto::hmac::hmac;
     use rustc_serialize::hex::FromHex;
-    use conf::Project;
+    use serde_json;
+    use ini::Ini;
+    use conf::Conf;
     use header::HubSignature;
     use error::{Result, Reason, Error};
+    use status::Status;
+    use job::{Job, Trigger};
 
     const PAYLOAD: &'static str = "{ \"repository\": { \"full_name\": \"Lepovirta/koukku\" }, \
                                    \"ref\": \"ref/heads/master\" }";
@@ -172,21 +599,29 @@ mod tests {
 
     const UNKNOWN_REPO: &'static str = "{ \"repository\": { \"full_name\": \"Lepovirta/lepo\" }, \
                                         \"ref\": \"ref/heads/master\" }";
-    const KEY: &'static str = "foobar";
-    const REPO: &'static str = "Lepovirta/koukku";
+    const NO_REPO: &'static str = "{ \"ref\": \"ref/heads/master\" }";
+    const NO_REPO_HEX_SHA1: &'static str = "c7731fdf7ad55ebc1b416c02492f45d43fae09f5";
+
+    const MAX_BODY_SIZE: u64 = 1024;
+    const PROJECT_ID: &'static str = "koukku";
+
+    const CONF: &'static str = "location = /tmp/koukku\n\
+                                max_body_size = 1024\n\
+                                [koukku]\n\
+                                repo = Lepovirta/koukku\n\
+                                key = foobar\n\
+                                branch = master\n\
+                                command = dostuff.sh\n";
+
+    fn setup() -> (WebhookHandler, Receiver<Job>) {
+        setup_with(CONF)
+    }
 
-    fn setup() -> (WebhookHandler, Receiver<String>) {
+    fn setup_with(conf_str: &str) -> (WebhookHandler, Receiver<Job>) {
         let (tx, rx) = channel();
-        let mut m = HashMap::new();
-        let project = Project {
-            id: "koukku".to_owned(),
-            repo: REPO.to_owned(),
-            branch: "master".to_owned(),
-            key: KEY.to_owned(),
-            command: "dostuff.sh".to_owned(),
-        };
-        m.insert(project.repo.to_owned(), project);
-        (WebhookHandler::new(m, tx), rx)
+        let ini = Ini::load_from_str(conf_str).unwrap();
+        let conf = Conf::from_ini(&ini).unwrap();
+        (WebhookHandler::new(&conf, tx, Arc::new(Status::new())), rx)
     }
 
     fn sha1sig(sha1str: &str) -> HubSignature {
@@ -197,6 +632,27 @@ mod tests {
         }
     }
 
+    // Manual requests are signed when the test runs, since they carry the current time
+    fn signed(fields: &str, timestamp: u64, nonce: &str) -> (Cursor<Vec<u8>>, HubSignature) {
+        let body = format!("{{ {}\"timestamp\": {}, \"nonce\": \"{}\" }}",
+                           fields,
+                           timestamp,
+                           nonce);
+        let signature = HubSignature {
+            digest: Type::SHA1,
+            hash: hmac(Type::SHA1, b"foobar", body.as_bytes()),
+        };
+        (cursor_from_str(&body), signature)
+    }
+
+    fn now() -> u64 {
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+    }
+
+    fn push_payload(handler: &WebhookHandler) -> Result<Outcome> {
+        handler.push(None, None, &mut cursor_from_str(PAYLOAD), &sha1sig(HEX_SHA1))
+    }
+
     fn cursor_from_str(contents: &str) -> Cursor<Vec<u8>> {
         let payload: Vec<u8> = contents.to_owned().into();
         Cursor::new(payload)
@@ -218,10 +674,10 @@ mod tests {
         let sig = sha1sig(HEX_SHA1);
         let (handler, rx) = setup();
 
-        let result = handler.push(&mut cursor, &sig);
+        let result = handler.push(None, None, &mut cursor, &sig);
 
         assert!(result.is_ok(), "result = {:?}", result);
-        assert_eq!(rx.recv().unwrap(), REPO);
+        assert_eq!(rx.recv().unwrap().project, PROJECT_ID);
     }
 
     #[test]
@@ -230,7 +686,7 @@ mod tests {
         let sig = sha1sig(HEX_SHA1);
         let (handler, rx) = setup();
 
-        let result = handler.push(&mut cursor, &sig);
+        let result = handler.push(None, None, &mut cursor, &sig);
 
         assert_reason(&result, Reason::MissingFields);
         assert!(rx.try_recv().is_err());
@@ -242,7 +698,7 @@ mod tests {
         let sig = sha1sig(INVALID_HEX_SHA1);
         let (handler, rx) = setup();
 
-        let result = handler.push(&mut cursor, &sig);
+        let result = handler.push(None, None, &mut cursor, &sig);
 
         assert_reason(&result, Reason::InvalidSignature);
         assert!(rx.try_recv().is_err());
@@ -254,21 +710,231 @@ mod tests {
         let sig = sha1sig(HEX_SHA1);
         let (handler, rx) = setup();
 
-        let result = handler.push(&mut cursor, &sig);
+        let result = handler.push(None, None, &mut cursor, &sig);
 
         assert_reason(&result, Reason::MissingProject);
         assert!(rx.try_recv().is_err());
     }
 
     #[test]
-    fn incorrect_branch() {
+    fn untracked_branch() {
         let mut cursor = cursor_from_str(INVALID_BRANCH);
         let sig = sha1sig(INVALID_BRANCH_HEX_SHA1);
         let (handler, rx) = setup();
 
-        let result = handler.push(&mut cursor, &sig);
+        let result = handler.push(None, None, &mut cursor, &sig);
+
+        assert_eq!(result.unwrap(), Outcome::Ignored("untracked_branch".to_owned()));
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn too_large_payload() {
+        let padding: String = iter::repeat(' ').take(MAX_BODY_SIZE as usize).collect();
+        let mut cursor = cursor_from_str(&format!("{}{}", PAYLOAD, padding));
+        let sig = sha1sig(HEX_SHA1);
+        let (handler, rx) = setup();
+
+        let result = handler.push(None, None, &mut cursor, &sig);
+
+        assert_reason(&result, Reason::PayloadTooLarge);
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn rate_limited_project() {
+        let conf = format!("{}rate_limit = 1\n", CONF);
+        let (handler, rx) = setup_with(&conf);
+
+        let result = push_payload(&handler);
+        assert!(result.is_ok(), "result = {:?}", result);
+        assert_eq!(rx.recv().unwrap().project, PROJECT_ID);
+
+        let result = push_payload(&handler);
+        match result {
+            Err(Error::RateLimited(_, retry_after)) => assert!(retry_after > 0),
+            other => panic!("Expected rate limit error, but got {:?}", other),
+        }
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn error_statuses() {
+        let signature = Error::app(Reason::InvalidSignature, "bad signature");
+        let project = Error::app(Reason::MissingProject, "no project");
+        let malformed = Error::app(Reason::InvalidRevision, "bad revision");
+        let unknown = Error::app(Reason::MissingRevision, "no revision");
+        let channel = Error::Channel("closed".to_owned());
+
+        assert_eq!(error_status(&signature), StatusCode::Forbidden);
+        assert_eq!(error_status(&project), StatusCode::NotFound);
+        assert_eq!(error_status(&malformed), StatusCode::BadRequest);
+        assert_eq!(error_status(&unknown), StatusCode::NotFound);
+        assert_eq!(error_status(&channel), StatusCode::InternalServerError);
+    }
+
+    #[test]
+    fn error_body_hides_message() {
+        let err = Error::app(Reason::InvalidSignature, "Expected hash 1234");
+
+        let body = serde_json::to_string(&error_json(&err)).unwrap();
+
+        assert_eq!(body, "{\"code\":\"invalid_signature\",\"status\":\"error\"}");
+    }
+
+    #[test]
+    fn routed_project_without_repo_name() {
+        let conf = format!("{}path = /hooks/koukku/\n", CONF);
+        let (handler, rx) = setup_with(&conf);
+        let route = handler.route("/hooks/koukku").unwrap();
+        let mut cursor = cursor_from_str(NO_REPO);
+        let sig = sha1sig(NO_REPO_HEX_SHA1);
+
+        let result = handler.push(route, None, &mut cursor, &sig);
+
+        assert_eq!(result.unwrap(), Outcome::Triggered);
+        assert_eq!(rx.recv().unwrap().project, PROJECT_ID);
+    }
+
+    #[test]
+    fn only_root_path_falls_back_to_repo_name() {
+        let (handler, _) = setup();
+
+        assert!(handler.route("/").unwrap().is_none());
+        assert_reason(&handler.route("/hooks/unknown").map(|_| ()),
+                      Reason::MissingProject);
+    }
+
+    #[test]
+    fn routed_project_not_found_by_repo_name() {
+        let conf = format!("{}path = /hooks/koukku\n", CONF);
+        let (handler, rx) = setup_with(&conf);
+
+        assert!(handler.route("/").is_err());
+        assert!(handler.route("/hooks/other").is_err());
+        let result = push_payload(&handler);
+        assert_reason(&result, Reason::MissingProject);
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn request_paths() {
+        let uri = RequestUri::AbsolutePath("/hooks/site/?foo=bar".to_owned());
+
+        assert_eq!(request_path(&uri).unwrap(), "/hooks/site");
+        assert!(request_path(&RequestUri::Star).is_err());
+    }
+
+    #[test]
+    fn readiness() {
+        let (handler, _rx) = setup();
+        assert_eq!(reply_status(handler.readiness()), StatusCode::ServiceUnavailable);
+
+        let _running = handler.status.executor_running();
+        assert_eq!(reply_status(handler.readiness()), StatusCode::Ok);
+
+        for _ in 0..handler.ready_queue_size {
+            handler.status.job_queued();
+        }
+        assert_eq!(reply_status(handler.readiness()), StatusCode::ServiceUnavailable);
+    }
+
+    fn reply_status(reply: Reply) -> StatusCode {
+        match reply {
+            Reply::Json(status, _) | Reply::Text(status, _) => status,
+        }
+    }
+
+    #[test]
+    fn job_carries_delivery() {
+        let mut cursor = cursor_from_str(PAYLOAD);
+        let sig = sha1sig(HEX_SHA1);
+        let (handler, rx) = setup();
+
+        let result = handler.push(None, Some("delivery-1".to_owned()), &mut cursor, &sig);
+
+        assert!(result.is_ok(), "result = {:?}", result);
+        assert_eq!(rx.recv().unwrap().delivery, Some("delivery-1".to_owned()));
+    }
+
+    #[test]
+    fn manual_trigger() {
+        let (mut cursor, sig) = signed("\"commit\": \"abc123\", ", now(), "n1");
+        let (handler, rx) = setup();
+        let project = handler.projects.get(PROJECT_ID).unwrap();
+
+        let result = handler.trigger(project, Action::Trigger, &mut cursor, &sig);
+
+        assert_eq!(result.unwrap(), Outcome::Triggered);
+        let job = rx.recv().unwrap();
+        assert_eq!(job.trigger, Trigger::Manual);
+        assert_eq!(job.revision, Some("abc123".to_owned()));
+        assert_eq!(job.branch, None);
+    }
+
+    #[test]
+    fn manual_trigger_with_invalid_signature() {
+        let (mut cursor, _) = signed("\"commit\": \"abc123\", ", now(), "n1");
+        let sig = sha1sig(INVALID_HEX_SHA1);
+        let (handler, rx) = setup();
+        let project = handler.projects.get(PROJECT_ID).unwrap();
+
+        let result = handler.trigger(project, Action::Trigger, &mut cursor, &sig);
+
+        assert_reason(&result, Reason::InvalidSignature);
+        assert!(rx.try_recv().is_err());
+    }
 
-        assert_reason(&result, Reason::InvalidBranch);
+    #[test]
+    fn replayed_manual_trigger() {
+        let (handler, rx) = setup();
+        let project = handler.projects.get(PROJECT_ID).unwrap();
+        let (mut cursor, sig) = signed("", now(), "n1");
+        let (mut replayed, _) = signed("", now(), "n1");
+        let (mut stale, stale_sig) = signed("", now() - 3600, "n2");
+
+        assert!(handler.trigger(project, Action::Trigger, &mut cursor, &sig).is_ok());
+        let replay = handler.trigger(project, Action::Trigger, &mut replayed, &sig);
+        let stale = handler.trigger(project, Action::Trigger, &mut stale, &stale_sig);
+
+        assert_reason(&replay, Reason::ReplayedRequest);
+        assert_reason(&stale, Reason::ReplayedRequest);
+        assert!(rx.try_recv().is_ok());
         assert!(rx.try_recv().is_err());
     }
+
+    #[test]
+    fn manual_trigger_with_invalid_revision() {
+        let (mut cursor, sig) = signed("\"commit\": \"--upload-pack=touch\", ", now(), "n1");
+        let (handler, rx) = setup();
+        let project = handler.projects.get(PROJECT_ID).unwrap();
+
+        let result = handler.trigger(project, Action::Trigger, &mut cursor, &sig);
+
+        assert_reason(&result, Reason::InvalidRevision);
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn rollback_without_history() {
+        let (mut cursor, sig) = signed("", now(), "n1");
+        let (handler, rx) = setup_with(&CONF.replace("/tmp/koukku", "/nonexistent/koukku"));
+        let project = handler.projects.get(PROJECT_ID).unwrap();
+
+        let result = handler.trigger(project, Action::Rollback, &mut cursor, &sig);
+
+        assert_reason(&result, Reason::MissingRevision);
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn trigger_targets() {
+        assert_eq!(trigger_target(&Method::Post, "/projects/site/trigger"),
+                   Some(("site".to_owned(), Action::Trigger)));
+        assert_eq!(trigger_target(&Method::Post, "/projects/site/rollback"),
+                   Some(("site".to_owned(), Action::Rollback)));
+        assert_eq!(trigger_target(&Method::Get, "/projects/site/trigger"), None);
+        assert_eq!(trigger_target(&Method::Post, "/projects/site/other"), None);
+        assert_eq!(trigger_target(&Method::Post, "/hooks/site"), None);
+    }
 }
@@ -0,0 +1,57 @@
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use metrics::Metrics;
+
+/// Runtime state shared between the web server and the executor
+pub struct Status {
+    pub metrics: Metrics,
+    executor_alive: AtomicBool,
+    queue_depth: AtomicUsize,
+}
+
+impl Status {
+    pub fn new() -> Status {
+        Status {
+            metrics: Metrics::new(),
+            executor_alive: AtomicBool::new(false),
+            queue_depth: AtomicUsize::new(0),
+        }
+    }
+
+    /// Marks the executor alive until the returned guard is dropped.
+    /// The guard is also dropped when the executor thread panics.
+    pub fn executor_running(&self) -> ExecutorGuard {
+        self.executor_alive.store(true, Ordering::SeqCst);
+        ExecutorGuard { status: self }
+    }
+
+    pub fn is_executor_alive(&self) -> bool {
+        self.executor_alive.load(Ordering::SeqCst)
+    }
+
+    pub fn job_queued(&self) {
+        self.queue_depth.fetch_add(1, Ordering::SeqCst);
+    }
+
+    pub fn job_dequeued(&self) {
+        self.queue_depth.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    pub fn queue_depth(&self) -> usize {
+        self.queue_depth.load(Ordering::SeqCst)
+    }
+
+    pub fn render_metrics(&self) -> String {
+        self.metrics.render(self.queue_depth())
+    }
+}
+
+pub struct ExecutorGuard<'a> {
+    status: &'a Status,
+}
+
+impl<'a> Drop for ExecutorGuard<'a> {
+    fn drop(&mut self) {
+        self.status.executor_alive.store(false, Ordering::SeqCst);
+    }
+}